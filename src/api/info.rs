@@ -7,29 +7,54 @@ use axum::{
 use serde::Deserialize;
 use tokio::sync::RwLock;
 
-use crate::{helper::PhixivError, pixiv::ArtworkListing, state::PhixivState};
+use crate::{
+    helper::{request_host, ApiError},
+    pixiv::ArtworkListing,
+    state::PhixivState,
+};
 
 #[derive(Deserialize)]
 pub struct ArtworkInfoPath {
     pub language: Option<String>,
     pub id: String,
+    #[serde(default)]
+    pub raw: bool,
 }
 
 pub(super) async fn artwork_info_handler(
     State(state): State<Arc<RwLock<PhixivState>>>,
     Query(path): Query<ArtworkInfoPath>,
-    Host(host): Host,
-) -> Result<Json<ArtworkListing>, PhixivError> {
-    let state = state.read().await;
-
-    Ok(Json(
-        ArtworkListing::get_listing(
-            path.language,
-            path.id,
-            &state.auth.access_token,
-            &host,
-            &state.client,
-        )
-        .await?,
-    ))
+    host: Option<Host>,
+) -> Result<Json<ArtworkListing>, ApiError> {
+    let host = request_host(host);
+
+    let state = state.read().await.clone();
+
+    let mut listing = ArtworkListing::get_listing_coalesced(
+        &state.listing_flights,
+        &state.pinned_artworks,
+        path.language,
+        path.id.clone(),
+        state.auth.access_token.clone(),
+        host,
+        state.client.clone(),
+    )
+    .await?;
+
+    // The animation details are optional, a snapshot may already carry them.
+    if listing.ugoira_meta.is_none() {
+        listing.ugoira_meta = match listing.ugoira_meta(&path.id, &state.client).await {
+            Ok(meta) => meta,
+            Err(e) => {
+                tracing::warn!("Failed to fetch ugoira meta for {}: {e}", path.id);
+                None
+            }
+        };
+    }
+
+    if path.raw {
+        listing.image_proxy_urls = listing.raw_image_urls.clone();
+    }
+
+    Ok(Json(listing))
 }
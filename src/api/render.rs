@@ -0,0 +1,66 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Host, Query, State},
+    headers::UserAgent,
+    Json, TypedHeader,
+};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use crate::{
+    embed::{self, RenderedArtwork, EMBED_CACHE_CONTROL},
+    helper::{request_host, ApiError, StatusError},
+    pixiv::{ArtworkPath, RawArtworkPath},
+    state::PhixivState,
+};
+
+#[derive(Deserialize)]
+pub struct ArtworkRenderQuery {
+    pub language: Option<String>,
+    pub id: String,
+    pub index: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ArtworkRender {
+    html: String,
+    content_type: &'static str,
+    cache_control: &'static str,
+}
+
+pub(super) async fn artwork_render_handler(
+    State(state): State<Arc<RwLock<PhixivState>>>,
+    Query(query): Query<ArtworkRenderQuery>,
+    user_agent: Option<TypedHeader<UserAgent>>,
+    host: Option<Host>,
+) -> Result<Json<ArtworkRender>, ApiError> {
+    let host = request_host(host);
+
+    let path: ArtworkPath = RawArtworkPath {
+        language: query.language,
+        id: query.id,
+        image_index: query.index,
+    }
+    .try_into()?;
+
+    let card_type = user_agent
+        .map(|TypedHeader(user_agent)| embed::card_type(&user_agent))
+        .unwrap_or("summary_large_image");
+
+    let state = state.read().await.clone();
+
+    match embed::render_artwork(path, &state, host, card_type, false).await? {
+        RenderedArtwork::Embed(embed) => Ok(Json(ArtworkRender {
+            html: embed.html,
+            content_type: "text/html; charset=utf-8",
+            cache_control: EMBED_CACHE_CONTROL,
+        })),
+        RenderedArtwork::Redirect(_) => Err(StatusError::new(
+            StatusCode::FORBIDDEN,
+            "sensitive artworks are not rendered on this host",
+        )
+        .into()),
+    }
+}
@@ -35,7 +35,7 @@ pub struct PixivAuth {
 }
 
 impl PixivAuth {
-    async fn authorize(client: &Client, refresh_token: &String) -> anyhow::Result<AuthResponse> {
+    async fn authorize(client: &Client, refresh_token: &str) -> anyhow::Result<AuthResponse> {
         let form_data = HashMap::from([
             ("client_id", CLIENT_ID),
             ("client_secret", CLIENT_SECRET),
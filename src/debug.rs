@@ -0,0 +1,77 @@
+use std::{env, sync::Arc};
+
+use axum::{
+    extract::{Host, Query, State},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use http::{header::CONTENT_TYPE, StatusCode};
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use crate::{
+    helper::{request_host, ApiError, StatusError},
+    pixiv::{ArtworkListing, EmbedFields},
+    state::{authorized_middleware, PhixivState},
+};
+
+#[derive(Deserialize)]
+pub struct DebugListingQuery {
+    pub language: Option<String>,
+    pub id: String,
+    pub index: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct DebugListing {
+    listing: ArtworkListing,
+    raw_image_urls: Vec<String>,
+    embed: EmbedFields,
+}
+
+fn debug_enabled() -> bool {
+    env::var("DEBUG_ENDPOINTS").unwrap_or_else(|_| String::from("false")) == "true"
+}
+
+async fn debug_listing_handler(
+    State(state): State<Arc<RwLock<PhixivState>>>,
+    Query(query): Query<DebugListingQuery>,
+    host: Option<Host>,
+) -> Result<Response, ApiError> {
+    let host = request_host(host);
+
+    if !debug_enabled() {
+        return Err(StatusError::new(StatusCode::NOT_FOUND, "debug endpoints are disabled").into());
+    }
+
+    let state = state.read().await.clone();
+
+    let listing = ArtworkListing::get_listing_coalesced(
+        &state.listing_flights,
+        &state.pinned_artworks,
+        query.language,
+        query.id,
+        state.auth.access_token.clone(),
+        host.clone(),
+        state.client.clone(),
+    )
+    .await?;
+
+    let embed = listing.embed_fields(query.index, host, "summary_large_image");
+
+    let body = serde_json::to_string_pretty(&DebugListing {
+        raw_image_urls: listing.raw_image_urls.clone(),
+        listing,
+        embed,
+    })?;
+
+    Ok(([(CONTENT_TYPE, "application/json")], body).into_response())
+}
+
+pub fn debug_router(state: Arc<RwLock<PhixivState>>) -> Router<Arc<RwLock<PhixivState>>> {
+    Router::new()
+        .route("/listing", get(debug_listing_handler))
+        .layer(middleware::from_fn_with_state(state, authorized_middleware))
+}
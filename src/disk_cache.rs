@@ -0,0 +1,144 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    env,
+    hash::{Hash, Hasher},
+    io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use bytes::Bytes;
+
+use crate::helper;
+
+/// Directory proxied images are cached in, configured with `PROXY_CACHE_DIR`. Caching is off when unset.
+pub fn cache_dir() -> Option<PathBuf> {
+    env::var("PROXY_CACHE_DIR")
+        .ok()
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Total size the cache is trimmed down to, configured with `PROXY_CACHE_MAX_BYTES`.
+fn max_bytes() -> u64 {
+    helper::env_or("PROXY_CACHE_MAX_BYTES", 1024 * 1024 * 1024)
+}
+
+/// Largest single image written to the cache, configured with `PROXY_CACHE_MAX_ENTRY_BYTES`.
+pub fn max_entry_bytes() -> u64 {
+    helper::env_or("PROXY_CACHE_MAX_ENTRY_BYTES", 32 * 1024 * 1024)
+}
+
+/// A cached proxy response, the body alongside the `Content-Type` pximg sent with it.
+pub struct CachedImage {
+    pub content_type: Option<String>,
+    pub bytes: Bytes,
+}
+
+fn entry_path(dir: &Path, key: &str) -> PathBuf {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+
+    dir.join(format!("{:016x}", hasher.finish()))
+}
+
+/// The content type is kept in a sidecar file next to the body.
+fn content_type_path(path: &Path) -> PathBuf {
+    path.with_extension("type")
+}
+
+/// Cached response for `key`, marking the entry as recently used.
+pub async fn get(dir: &Path, key: &str) -> Option<CachedImage> {
+    let path = entry_path(dir, key);
+    let bytes = tokio::fs::read(&path).await.ok()?;
+    let content_type = tokio::fs::read_to_string(content_type_path(&path))
+        .await
+        .ok();
+
+    // Eviction goes by modification time, touch the entry so popular images stay cached.
+    let touched = tokio::task::spawn_blocking(move || {
+        std::fs::File::options()
+            .write(true)
+            .open(&path)?
+            .set_modified(SystemTime::now())
+    })
+    .await;
+
+    if let Ok(Err(e)) = touched {
+        tracing::warn!("Failed to touch proxy cache entry: {e}");
+    }
+
+    Some(CachedImage {
+        content_type,
+        bytes: Bytes::from(bytes),
+    })
+}
+
+/// Stores `image` under `key`, then evicts the least recently used entries past `PROXY_CACHE_MAX_BYTES`.
+pub async fn insert(dir: PathBuf, key: String, image: CachedImage) {
+    let result = async {
+        tokio::fs::create_dir_all(&dir).await?;
+
+        // Write to a temporary file first so readers never see a partial image.
+        let path = entry_path(&dir, &key);
+        let partial = path.with_extension("partial");
+
+        if let Some(content_type) = &image.content_type {
+            tokio::fs::write(content_type_path(&path), content_type).await?;
+        }
+
+        tokio::fs::write(&partial, &image.bytes).await?;
+        tokio::fs::rename(&partial, &path).await?;
+
+        tokio::task::spawn_blocking(move || evict(&dir))
+            .await
+            .map_err(io::Error::other)?
+    }
+    .await;
+
+    if let Err(e) = result {
+        tracing::warn!("Failed to write proxy cache entry: {e}");
+    }
+}
+
+fn evict(dir: &Path) -> io::Result<()> {
+    let mut entries = Vec::new();
+
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        // Another eviction may have removed the entry since it was listed.
+        let metadata = match entry.metadata() {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            metadata => metadata?,
+        };
+
+        if metadata.is_file() && entry.path().extension().is_none() {
+            entries.push((metadata.modified()?, metadata.len(), entry.path()));
+        }
+    }
+
+    let mut total: u64 = entries.iter().map(|(_, len, _)| len).sum();
+    let max_bytes = max_bytes();
+
+    entries.sort_by_key(|(modified, _, _)| *modified);
+
+    for (_, len, path) in entries {
+        if total <= max_bytes {
+            break;
+        }
+
+        remove_if_exists(&path)?;
+        remove_if_exists(&content_type_path(&path))?;
+        total -= len;
+    }
+
+    Ok(())
+}
+
+/// Removes `path`, treating an entry already removed by a concurrent eviction as success.
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
@@ -1,75 +1,324 @@
-use std::{env, sync::Arc};
+use std::{
+    collections::HashMap,
+    env,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
+use askama::Template;
 use axum::{
+    extract::DefaultBodyLimit,
     extract::{Host, OriginalUri, Path, Query, State},
-    headers::{CacheControl, UserAgent},
+    headers::UserAgent,
     middleware,
     response::{Html, IntoResponse, Redirect, Response},
     routing::get,
     Router, TypedHeader,
 };
-use http::Uri;
+use http::{
+    header::{CACHE_CONTROL, LINK},
+    StatusCode, Uri,
+};
 use serde::Deserialize;
 use tokio::sync::RwLock;
+use urlencoding::encode;
 
 use crate::{
-    helper::PhixivError,
+    helper::{self, PhixivError, StatusError},
     pixiv::{ArtworkListing, ArtworkPath, RawArtworkPath},
     state::{authorized_middleware, PhixivState},
 };
 
+/// Request body limit for the embed and proxy routes, they only serve GETs.
+pub const EMBED_BODY_LIMIT: usize = 1024;
+
+/// Crawlers that lay out `summary` cards better than `summary_large_image`, matched against the user agent.
+const SUMMARY_CARD_AGENTS: [&str; 2] = ["Slackbot", "TelegramBot"];
+
+pub fn card_type(user_agent: &UserAgent) -> &'static str {
+    if SUMMARY_CARD_AGENTS
+        .iter()
+        .any(|agent| user_agent.as_str().contains(agent))
+    {
+        "summary"
+    } else {
+        "summary_large_image"
+    }
+}
+
+/// Cache-Control sent with rendered embeds, crawlers should always come back for a fresh page.
+pub const EMBED_CACHE_CONTROL: &str = "no-cache";
+
+/// Rendered embed and its oembed link, reused for identical requests within `RENDER_CACHE_TTL` seconds.
+#[derive(Clone)]
+pub struct RenderedEmbed {
+    rendered_at: Instant,
+    pub oembed_link: String,
+    pub html: String,
+}
+
+/// Result of rendering an artwork for a host.
+pub enum RenderedArtwork {
+    Embed(RenderedEmbed),
+    /// The artwork is not embedded on this host, clients are sent to pixiv instead.
+    Redirect(String),
+}
+
+/// Rendered embeds keyed by language, illust id, index, host and card type.
+#[derive(Clone, Default)]
+pub struct RenderCache(Arc<Mutex<HashMap<String, RenderedEmbed>>>);
+
+impl RenderCache {
+    fn ttl() -> Duration {
+        Duration::from_secs(helper::env_or("RENDER_CACHE_TTL", 60))
+    }
+
+    fn get(&self, key: &str) -> Option<RenderedEmbed> {
+        self.0
+            .lock()
+            .unwrap()
+            .get(key)
+            .filter(|embed| embed.rendered_at.elapsed() < Self::ttl())
+            .cloned()
+    }
+
+    fn insert(&self, key: String, embed: RenderedEmbed) {
+        let max_entries = helper::env_or("RENDER_CACHE_SIZE", 512usize);
+
+        if max_entries == 0 {
+            return;
+        }
+
+        let mut cache = self.0.lock().unwrap();
+        let ttl = Self::ttl();
+
+        cache.retain(|_, embed| embed.rendered_at.elapsed() < ttl);
+
+        if cache.len() >= max_entries {
+            let oldest = cache
+                .iter()
+                .min_by_key(|(_, embed)| embed.rendered_at)
+                .map(|(key, _)| key.clone());
+
+            if let Some(oldest) = oldest {
+                cache.remove(&oldest);
+            }
+        }
+
+        cache.insert(key, embed);
+    }
+}
+
+fn embed_response(embed: RenderedEmbed) -> Response {
+    (
+        [
+            (CACHE_CONTROL, String::from(EMBED_CACHE_CONTROL)),
+            (LINK, embed.oembed_link),
+        ],
+        Html(embed.html),
+    )
+        .into_response()
+}
+
 async fn artwork_response(
     raw_path: RawArtworkPath,
     state: Arc<RwLock<PhixivState>>,
     host: String,
+    card_type: &str,
+    refresh: bool,
 ) -> anyhow::Result<Response> {
-    let path: ArtworkPath = raw_path.try_into()?;
+    let original_path = format!(
+        "{}/artworks/{}",
+        raw_path
+            .language
+            .as_ref()
+            .map(|l| format!("/{l}"))
+            .unwrap_or_default(),
+        raw_path.id
+    );
 
-    let state = state.read().await;
+    let Ok(path): Result<ArtworkPath, _> = raw_path.try_into() else {
+        return not_found(original_path, &host);
+    };
+
+    // Clone the state out so a token refresh waiting on the write lock does not stall on pixiv.
+    let state = state.read().await.clone();
+
+    let artwork_url = format!("https://www.pixiv.net/artworks/{}", path.id);
 
-    let listing = ArtworkListing::get_listing(
+    match render_artwork(path, &state, host.clone(), card_type, refresh).await {
+        Ok(RenderedArtwork::Embed(embed)) => Ok(embed_response(embed)),
+        Ok(RenderedArtwork::Redirect(url)) => Ok(Redirect::temporary(&url).into_response()),
+        Err(e) if helper::error_status(&e) == StatusCode::FORBIDDEN => {
+            tracing::info!("Artwork {artwork_url} is unavailable: {e:#}");
+            unavailable(artwork_url, &host)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Renders the embed for `path`, reusing a cached render unless `refresh` is set.
+pub async fn render_artwork(
+    path: ArtworkPath,
+    state: &PhixivState,
+    host: String,
+    card_type: &str,
+    refresh: bool,
+) -> anyhow::Result<RenderedArtwork> {
+    let render_key = format!(
+        "{}_{}_{}_{host}_{card_type}",
+        path.language
+            .as_deref()
+            .map(str::to_lowercase)
+            .unwrap_or_else(helper::default_language),
+        path.id,
+        path.image_index.map(|i| i.to_string()).unwrap_or_default(),
+    );
+
+    // A refresh skips the cached render, the new render then replaces it.
+    if !refresh {
+        if let Some(embed) = state.render_cache.get(&render_key) {
+            return Ok(RenderedArtwork::Embed(embed));
+        }
+    }
+
+    let listing = ArtworkListing::get_listing_coalesced(
+        &state.listing_flights,
+        &state.pinned_artworks,
         path.language,
         path.id,
-        &state.auth.access_token,
-        &host,
-        &state.client,
+        state.auth.access_token.clone(),
+        host.clone(),
+        state.client.clone(),
     )
     .await?;
 
-    let artwork = listing.to_template(path.image_index, host).unwrap();
+    // The sfw. subdomain never renders sensitive works, nor does any host with NSFW_MODE=block,
+    // those go straight to pixiv.
+    let block_nsfw = host.starts_with("sfw.") || env::var("NSFW_MODE").as_deref() == Ok("block");
+
+    if block_nsfw && listing.x_restrict > 0 {
+        return Ok(RenderedArtwork::Redirect(listing.url));
+    }
+
+    listing.check_index(path.image_index)?;
+
+    let oembed_link = format!(
+        "<{}://{host}/e?i={}&n={}>; rel=\"alternate\"; type=\"application/json+oembed\"",
+        helper::scheme(),
+        encode(&listing.author_id),
+        encode(&listing.author_name),
+    );
 
-    Ok((
-        TypedHeader(CacheControl::new().with_no_cache()),
-        Html(artwork),
+    let artwork = listing.to_template(path.image_index, host, card_type)?;
+
+    let embed = RenderedEmbed {
+        rendered_at: Instant::now(),
+        oembed_link,
+        html: artwork,
+    };
+
+    state.render_cache.insert(render_key, embed.clone());
+
+    Ok(RenderedArtwork::Embed(embed))
+}
+
+/// Redirects to the rendered video of an ugoira, static artworks have none.
+async fn video_response(
+    raw_path: RawArtworkPath,
+    state: Arc<RwLock<PhixivState>>,
+    host: String,
+) -> anyhow::Result<Response> {
+    let path: ArtworkPath = raw_path.try_into()?;
+
+    let state = state.read().await.clone();
+
+    let listing = ArtworkListing::get_listing_coalesced(
+        &state.listing_flights,
+        &state.pinned_artworks,
+        path.language,
+        path.id,
+        state.auth.access_token.clone(),
+        host,
+        state.client.clone(),
     )
-        .into_response())
+    .await?;
+
+    if !listing.is_ugoira || !helper::ugoira_enabled() {
+        anyhow::bail!(StatusError::new(
+            StatusCode::NOT_FOUND,
+            "artwork is not an ugoira",
+        ));
+    }
+
+    Ok(Redirect::temporary(&listing.image_proxy_urls[0]).into_response())
+}
+
+#[derive(Deserialize)]
+struct ArtworkQuery {
+    pub format: Option<String>,
+    /// Page index, for links where the index cannot go in the path. The path index takes precedence.
+    pub p: Option<String>,
+    /// Renders the embed again instead of serving it from the render cache, after an artist edits the work.
+    #[serde(default)]
+    pub refresh: bool,
 }
 
 async fn artwork_handler(
-    Path(path): Path<RawArtworkPath>,
+    Path(mut path): Path<RawArtworkPath>,
+    Query(query): Query<ArtworkQuery>,
     State(state): State<Arc<RwLock<PhixivState>>>,
     TypedHeader(user_agent): TypedHeader<UserAgent>,
-    Host(host): Host,
+    host: Option<Host>,
 ) -> Result<Response, PhixivError> {
+    let host = helper::request_host(host);
+
+    let mut wants_video = query.format.as_deref() == Some("mp4");
+
+    if let Some(id) = path.id.strip_suffix(".mp4") {
+        path.id = id.to_string();
+        wants_video = true;
+    }
+
+    if wants_video {
+        return Ok(video_response(path, state, host).await?);
+    }
+
+    if path.image_index.is_none() {
+        path.image_index = query.p;
+    }
+
+    let card_type = card_type(&user_agent);
+
     if let Some(resp) = filter_bots(user_agent, &path) {
         return Ok(resp);
     }
 
-    Ok(artwork_response(path, state, host).await?)
+    Ok(artwork_response(path, state, host, card_type, query.refresh).await?)
 }
 
+/// Query parameters pixiv has used for the artwork id on legacy pages, in order of preference.
+const ILLUST_ID_PARAMS: [&str; 3] = ["illust_id", "illustId", "id"];
+
+/// Query of a legacy artwork page, unrelated parameters such as `mode` or tracking tags are ignored.
 #[derive(Deserialize)]
-struct MemberIllustParams {
-    pub illust_id: String,
-}
+#[serde(transparent)]
+struct MemberIllustParams(HashMap<String, String>);
 
-impl From<MemberIllustParams> for RawArtworkPath {
-    fn from(params: MemberIllustParams) -> Self {
-        Self {
+impl TryFrom<MemberIllustParams> for RawArtworkPath {
+    type Error = StatusError;
+
+    fn try_from(mut params: MemberIllustParams) -> Result<Self, Self::Error> {
+        let id = ILLUST_ID_PARAMS
+            .iter()
+            .find_map(|name| params.0.remove(*name))
+            .ok_or_else(|| StatusError::new(StatusCode::NOT_FOUND, "missing illust_id"))?;
+
+        Ok(Self {
             language: None,
-            id: params.illust_id,
+            id,
             image_index: None,
-        }
+        })
     }
 }
 
@@ -77,15 +326,24 @@ async fn member_illust_handler(
     Query(params): Query<MemberIllustParams>,
     State(state): State<Arc<RwLock<PhixivState>>>,
     TypedHeader(user_agent): TypedHeader<UserAgent>,
-    Host(host): Host,
+    host: Option<Host>,
 ) -> Result<Response, PhixivError> {
-    let raw_path: RawArtworkPath = params.into();
+    let host = helper::request_host(host);
+
+    let raw_path: RawArtworkPath = params.try_into()?;
+
+    let card_type = card_type(&user_agent);
 
     if let Some(resp) = filter_bots(user_agent, &raw_path) {
         return Ok(resp);
     }
 
-    Ok(artwork_response(raw_path, state, host).await?)
+    Ok(artwork_response(raw_path, state, host, card_type, false).await?)
+}
+
+/// Legacy `/i/:id` artwork links.
+async fn legacy_id_handler(Path(id): Path<String>) -> Response {
+    Redirect::permanent(&format!("/artworks/{id}")).into_response()
 }
 
 fn filter_bots(user_agent: UserAgent, raw_path: &RawArtworkPath) -> Option<Response> {
@@ -113,6 +371,75 @@ fn filter_bots(user_agent: UserAgent, raw_path: &RawArtworkPath) -> Option<Respo
     None
 }
 
+#[derive(Template)]
+#[template(path = "notfound.html")]
+struct NotFoundTemplate {
+    path: String,
+    site_name: String,
+}
+
+#[derive(Template)]
+#[template(path = "region_locked.html")]
+struct UnavailableTemplate {
+    url: String,
+    site_name: String,
+}
+
+/// Card for artworks pixiv refuses to show us, served as a 200 so crawlers still unfurl it.
+fn unavailable(url: String, host: &str) -> anyhow::Result<Response> {
+    let page = UnavailableTemplate {
+        url,
+        site_name: helper::provider_name(host),
+    }
+    .render()?;
+
+    Ok(Html(page).into_response())
+}
+
+fn not_found(path: String, host: &str) -> anyhow::Result<Response> {
+    let page = NotFoundTemplate {
+        path,
+        site_name: helper::provider_name(host),
+    }
+    .render()?;
+
+    Ok((StatusCode::NOT_FOUND, Html(page)).into_response())
+}
+
+/// Number of single character edits needed to turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+
+        for (j, cb) in b.iter().enumerate() {
+            let current = row[j + 1];
+            row[j + 1] = if ca == *cb {
+                previous
+            } else {
+                1 + previous.min(row[j]).min(row[j + 1])
+            };
+            previous = current;
+        }
+    }
+
+    row[b.len()]
+}
+
+/// Whether `path` looks like a mistyped artwork link, such as `/artwroks/123`, rather than a pixiv page.
+fn is_malformed_artwork_path(path: &str) -> bool {
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+    segments.windows(2).any(|pair| {
+        let distance = edit_distance(&pair[0].to_lowercase(), "artworks");
+
+        (1..=2).contains(&distance) && pair[1].chars().all(|c| c.is_ascii_digit())
+    })
+}
+
 fn redirect_uri(uri: Uri) -> String {
     let Some(path_and_query) = uri.path_and_query() else {
         return String::from("https://www.pixiv.net/");
@@ -127,19 +454,84 @@ fn redirect_uri(uri: Uri) -> String {
         .to_string()
 }
 
-async fn redirect_fallback(OriginalUri(uri): OriginalUri) -> Redirect {
-    Redirect::temporary(&redirect_uri(uri))
+#[derive(Template)]
+#[template(path = "index.html")]
+struct IndexTemplate {
+    host: String,
+    scheme: String,
+    site_name: String,
+    title: String,
+    description: String,
+    image: Option<String>,
+}
+
+/// Root of the embed host, redirects to pixiv unless `LANDING_PAGE=html` asks for a usage page.
+async fn landing_handler(
+    OriginalUri(uri): OriginalUri,
+    host: Option<Host>,
+) -> Result<Response, PhixivError> {
+    let host = helper::request_host(host);
+
+    if env::var("LANDING_PAGE").as_deref() != Ok("html") {
+        return Ok(Redirect::temporary(&redirect_uri(uri)).into_response());
+    }
+
+    let site_name = helper::provider_name(&host);
+
+    let page = IndexTemplate {
+        title: env::var("LANDING_TITLE")
+            .ok()
+            .filter(|title| !title.is_empty())
+            .unwrap_or_else(|| site_name.clone()),
+        description: env::var("LANDING_DESCRIPTION")
+            .ok()
+            .filter(|description| !description.is_empty())
+            .unwrap_or_else(|| String::from("Fixes pixiv embeds on Discord and other sites.")),
+        image: env::var("LANDING_IMAGE").ok().filter(|url| !url.is_empty()),
+        site_name,
+        scheme: helper::scheme(),
+        host,
+    }
+    .render()?;
+
+    Ok(Html(page).into_response())
+}
+
+async fn redirect_fallback(
+    OriginalUri(uri): OriginalUri,
+    host: Option<Host>,
+) -> Result<Response, PhixivError> {
+    let host = helper::request_host(host);
+
+    if is_malformed_artwork_path(uri.path()) {
+        return Ok(not_found(uri.path().to_string(), &host)?);
+    }
+
+    Ok(Redirect::temporary(&redirect_uri(uri)).into_response())
 }
 
 pub fn router(
     state: Arc<RwLock<PhixivState>>,
 ) -> Router<Arc<RwLock<PhixivState>>, axum::body::Body> {
-    Router::new()
+    let router = Router::new()
+        .route("/", get(landing_handler))
         .route("/:language/artworks/:id", get(artwork_handler))
         .route("/:language/artworks/:id/:image_index", get(artwork_handler))
         .route("/artworks/:id", get(artwork_handler))
         .route("/artworks/:id/:image_index", get(artwork_handler))
         .route("/member_illust.php", get(member_illust_handler))
+        .route("/illust.php", get(member_illust_handler));
+
+    // The proxy answers legacy /i/:id links itself when it is mounted at /i.
+    let router = if helper::proxy_prefix() == "i" {
+        router
+    } else {
+        router.route("/i/:id", get(legacy_id_handler))
+    };
+
+    router
         .fallback(redirect_fallback)
         .layer(middleware::from_fn_with_state(state, authorized_middleware))
+        .layer(middleware::from_fn(helper::get_only_middleware))
+        .layer(DefaultBodyLimit::max(EMBED_BODY_LIMIT))
 }
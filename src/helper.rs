@@ -1,5 +1,26 @@
-use axum::response::{IntoResponse, Response};
-use http::{HeaderMap, HeaderValue, StatusCode};
+use std::{env, fmt};
+
+use axum::{
+    extract::Host,
+    headers::{authorization::Basic, Authorization, HeaderMapExt},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use http::{
+    header::{ACCESS_CONTROL_REQUEST_METHOD, ALLOW, WWW_AUTHENTICATE},
+    HeaderMap, HeaderValue, Method, Request, StatusCode,
+};
+use serde_json::json;
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+
+/// Value of `key` parsed as `T`, or `default` when unset or unparsable.
+pub fn env_or<T: std::str::FromStr>(key: &str, default: T) -> T {
+    env::var(key)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
 
 pub fn headers() -> HeaderMap<HeaderValue> {
     let mut headers = HeaderMap::with_capacity(5);
@@ -14,11 +35,238 @@ pub fn headers() -> HeaderMap<HeaderValue> {
     headers
 }
 
+const DEFAULT_PROVIDER_NAME: &str = "phixiv";
+const DEFAULT_PROVIDER_URL: &str = "https://github.com/thelaao/phixiv";
+
+/// Looks up the branding configured for `host` in `PROVIDER_MAP`.
+///
+/// The map is a comma separated list of `host=name|url` entries, the url part is optional.
+fn provider_override(host: &str) -> Option<(String, Option<String>)> {
+    let map = env::var("PROVIDER_MAP").ok()?;
+
+    map.split(',').find_map(|entry| {
+        let (entry_host, branding) = entry.trim().split_once('=')?;
+
+        if !entry_host.eq_ignore_ascii_case(host) {
+            return None;
+        }
+
+        match branding.split_once('|') {
+            Some((name, url)) => Some((name.to_string(), Some(url.to_string()))),
+            None => Some((branding.to_string(), None)),
+        }
+    })
+}
+
+pub fn provider_name(host: &str) -> String {
+    provider_override(host)
+        .map(|(name, _)| name)
+        .unwrap_or_else(|| {
+            env::var("PROVIDER_NAME").unwrap_or_else(|_| String::from(DEFAULT_PROVIDER_NAME))
+        })
+}
+
+pub fn provider_url(host: &str) -> String {
+    provider_override(host)
+        .and_then(|(_, url)| url)
+        .unwrap_or_else(|| {
+            env::var("PROVIDER_URL").unwrap_or_else(|_| String::from(DEFAULT_PROVIDER_URL))
+        })
+}
+
+/// Host the request was made to, or `CANONICAL_HOST` for clients that send no Host header.
+pub fn request_host(host: Option<Host>) -> String {
+    host.map(|Host(host)| host).unwrap_or_else(|| {
+        env::var("CANONICAL_HOST").unwrap_or_else(|_| String::from("phixiv.net"))
+    })
+}
+
+/// Profile url of the pixiv user `id`, configured with `AUTHOR_URL_TEMPLATE` where `{id}` is replaced by the id.
+pub fn author_url(id: &str) -> String {
+    env::var("AUTHOR_URL_TEMPLATE")
+        .ok()
+        .filter(|template| template.contains("{id}"))
+        .unwrap_or_else(|| String::from("https://www.pixiv.net/users/{id}"))
+        .replace("{id}", id)
+}
+
+/// Path segment the image proxy is mounted under, configured with `PROXY_PREFIX`.
+pub fn proxy_prefix() -> String {
+    env::var("PROXY_PREFIX")
+        .ok()
+        .map(|prefix| prefix.trim_matches('/').to_string())
+        .filter(|prefix| !prefix.is_empty())
+        .unwrap_or_else(|| String::from("i"))
+}
+
+/// Base url images are fetched from, configured with `PXIMG_BASE`.
+pub fn pximg_base() -> String {
+    env::var("PXIMG_BASE").unwrap_or_else(|_| String::from("https://i.pximg.net/"))
+}
+
+/// Scheme of the urls phixiv links back to itself with, configured with `SCHEME`.
+pub fn scheme() -> String {
+    match env::var("SCHEME").as_deref() {
+        Ok("http") => String::from("http"),
+        _ => String::from("https"),
+    }
+}
+
+/// Url of `path` on pximg, proxied through `host`.
+pub fn proxy_url(host: &str, path: &str) -> String {
+    format!("{}://{host}/{}{path}", scheme(), proxy_prefix())
+}
+
+/// Whether ugoira are embedded as videos, configured with `UGOIRA_ENABLED`.
+pub fn ugoira_enabled() -> bool {
+    env::var("UGOIRA_ENABLED").unwrap_or_else(|_| String::from("false")) == "true"
+}
+
+/// Whether sensitive works embed a blurred preview, configured with `BLUR_NSFW`.
+pub fn blur_nsfw() -> bool {
+    env::var("BLUR_NSFW").unwrap_or_else(|_| String::from("false")) == "true"
+}
+
+/// Languages accepted by the pixiv ajax api.
+const SUPPORTED_LANGUAGES: [&str; 5] = ["jp", "en", "zh", "zh_tw", "ko"];
+
+/// Languages tried in order when a translation is missing, configured with `LANGUAGE_FALLBACK_CHAIN`
+/// as a comma separated list such as `en,zh,jp`. Unsupported languages are skipped.
+pub fn language_fallback_chain() -> Vec<String> {
+    env::var("LANGUAGE_FALLBACK_CHAIN")
+        .unwrap_or_default()
+        .split(',')
+        .map(|language| language.trim().to_lowercase())
+        .filter(|language| SUPPORTED_LANGUAGES.contains(&language.as_str()))
+        .collect()
+}
+
+/// Language used when the request does not specify one, configured with `DEFAULT_LANGUAGE`,
+/// or the head of `LANGUAGE_FALLBACK_CHAIN` when that is unset.
+pub fn default_language() -> String {
+    match env::var("DEFAULT_LANGUAGE") {
+        Ok(language) if SUPPORTED_LANGUAGES.contains(&language.as_str()) => language,
+        Ok(language) => {
+            tracing::warn!("Unsupported DEFAULT_LANGUAGE {language}, falling back to jp");
+            String::from("jp")
+        }
+        Err(_) => language_fallback_chain()
+            .into_iter()
+            .next()
+            .unwrap_or_else(|| String::from("jp")),
+    }
+}
+
+/// CORS policy for the json endpoints, origins are configured with `CORS_ALLOW_ORIGINS` as a
+/// comma separated list or `*`. Cross origin requests are refused when unset.
+pub fn cors_layer() -> CorsLayer {
+    let origins = env::var("CORS_ALLOW_ORIGINS").unwrap_or_default();
+
+    let allow_origin = if origins.trim() == "*" {
+        AllowOrigin::from(Any)
+    } else {
+        AllowOrigin::list(
+            origins
+                .split(',')
+                .map(str::trim)
+                .filter(|origin| !origin.is_empty())
+                .filter_map(|origin| origin.parse::<HeaderValue>().ok()),
+        )
+    };
+
+    CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods([Method::GET, Method::HEAD])
+        .allow_headers(Any)
+}
+
+/// Rejects anything but GET and HEAD with a 405 listing the allowed methods.
+pub async fn get_only_middleware<B>(request: Request<B>, next: Next<B>) -> Response {
+    if matches!(*request.method(), Method::GET | Method::HEAD) {
+        return next.run(request).await;
+    }
+
+    (StatusCode::METHOD_NOT_ALLOWED, [(ALLOW, "GET, HEAD")]).into_response()
+}
+
+/// Requires the `user:pass` credentials from `BASIC_AUTH` when set, for private instances.
+pub async fn basic_auth_middleware<B>(request: Request<B>, next: Next<B>) -> Response {
+    let Some(credentials) = env::var("BASIC_AUTH").ok().filter(|c| !c.is_empty()) else {
+        return next.run(request).await;
+    };
+
+    // Browsers never send credentials with a CORS preflight, the CORS layer answers those.
+    let is_preflight = request.method() == Method::OPTIONS
+        && request
+            .headers()
+            .contains_key(ACCESS_CONTROL_REQUEST_METHOD);
+
+    if is_preflight {
+        return next.run(request).await;
+    }
+
+    let authorized = request
+        .headers()
+        .typed_get::<Authorization<Basic>>()
+        .is_some_and(|Authorization(basic)| {
+            credentials.split_once(':') == Some((basic.username(), basic.password()))
+        });
+
+    if authorized {
+        return next.run(request).await;
+    }
+
+    (
+        StatusCode::UNAUTHORIZED,
+        [(WWW_AUTHENTICATE, "Basic realm=\"phixiv\"")],
+    )
+        .into_response()
+}
+
+/// Error reported to the client with a specific status code instead of a 500.
+#[derive(Debug)]
+pub struct StatusError {
+    pub status: StatusCode,
+    pub message: String,
+}
+
+impl StatusError {
+    pub fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for StatusError {}
+
 pub struct PhixivError(anyhow::Error);
 
+/// Status of the first [`StatusError`] in the chain of `error`, 500 when there is none.
+pub fn error_status(error: &anyhow::Error) -> StatusCode {
+    error
+        .chain()
+        .find_map(|e| e.downcast_ref::<StatusError>())
+        .map(|e| e.status)
+        .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+impl PhixivError {
+    pub fn status(&self) -> StatusCode {
+        error_status(&self.0)
+    }
+}
+
 impl IntoResponse for PhixivError {
     fn into_response(self) -> Response {
-        (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", self.0)).into_response()
+        (self.status(), format!("{:#}", self.0)).into_response()
     }
 }
 
@@ -30,3 +278,27 @@ where
         Self(value.into())
     }
 }
+
+/// [`PhixivError`] reported as a json body, used by the api routes.
+pub struct ApiError(PhixivError);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = self.0.status();
+
+        (
+            status,
+            Json(json!({ "error": format!("{:#}", self.0 .0), "status": status.as_u16() })),
+        )
+            .into_response()
+    }
+}
+
+impl<E> From<E> for ApiError
+where
+    E: Into<anyhow::Error>,
+{
+    fn from(value: E) -> Self {
+        Self(PhixivError::from(value))
+    }
+}
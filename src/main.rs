@@ -1,16 +1,25 @@
 pub mod api;
 pub mod auth;
+pub mod debug;
+pub mod disk_cache;
 pub mod embed;
 pub mod helper;
 pub mod oembed;
 pub mod pixiv;
 pub mod proxy;
+pub mod report;
 pub mod state;
 
-use std::{env, net::SocketAddr, sync::Arc};
+use std::{env, net::SocketAddr, sync::Arc, time::Duration};
 
+use anyhow::Context;
 use api::api_router;
-use axum::{response::IntoResponse, routing::get, Json, Router};
+use axum::{
+    headers::CacheControl, middleware, response::IntoResponse, routing::get, Json, Router,
+    TypedHeader,
+};
+use debug::debug_router;
+use http::StatusCode;
 use oembed::oembed_handler;
 use proxy::proxy_router;
 use serde_json::json;
@@ -35,8 +44,11 @@ async fn main() -> anyhow::Result<()> {
     )
     .parse()?;
 
+    let json_logs = env::var("LOG_FORMAT").is_ok_and(|format| format == "json");
+
     let tracing_registry = tracing_subscriber::registry()
-        .with(fmt::layer())
+        .with(json_logs.then(|| fmt::layer().json()))
+        .with((!json_logs).then(fmt::layer))
         .with(EnvFilter::from_default_env());
 
     if let Ok(loki_url) = env::var("LOKI_URL") {
@@ -56,11 +68,36 @@ async fn main() -> anyhow::Result<()> {
 
     tracing::info!("Listening on: {addr}");
 
+    let refresh_token = env::var("PIXIV_REFRESH_TOKEN")
+        .ok()
+        .filter(|token| !token.is_empty())
+        .context(
+            "PIXIV_REFRESH_TOKEN is not set, a pixiv refresh token is required to fetch artworks",
+        )?;
+
     let state = Arc::new(RwLock::new(
-        PhixivState::login(env::var("PIXIV_REFRESH_TOKEN")?).await?,
+        PhixivState::login(refresh_token)
+            .await
+            .context("failed to log in to pixiv with PIXIV_REFRESH_TOKEN")?,
     ));
 
+    // HTTP/2 is accepted alongside HTTP/1.1, as h2c or from a TLS terminating proxy.
     axum::Server::bind(&addr)
+        .http1_keepalive(helper::env_or("HTTP1_KEEPALIVE", true))
+        // An interval of 0, the default, leaves HTTP/2 keep-alive pings off.
+        .http2_keep_alive_interval(
+            Some(helper::env_or("HTTP2_KEEPALIVE_INTERVAL", 0))
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs),
+        )
+        .http2_keep_alive_timeout(Duration::from_secs(helper::env_or(
+            "HTTP2_KEEPALIVE_TIMEOUT",
+            20,
+        )))
+        .tcp_keepalive(Some(Duration::from_secs(helper::env_or(
+            "SERVER_TCP_KEEPALIVE",
+            60,
+        ))))
         .serve(app(state).into_make_service())
         .with_graceful_shutdown(shutdown_signal())
         .await?;
@@ -71,10 +108,17 @@ async fn main() -> anyhow::Result<()> {
 fn app(state: Arc<RwLock<PhixivState>>) -> Router {
     Router::new()
         .merge(embed::router(state.clone()))
-        .route("/health", get(health))
-        .route("/e", get(oembed_handler))
-        .nest("/i", proxy_router(state.clone()))
+        .route("/favicon.ico", get(favicon))
+        .route("/e", get(oembed_handler).layer(helper::cors_layer()))
+        .nest(
+            &format!("/{}", helper::proxy_prefix()),
+            proxy_router(state.clone()),
+        )
         .nest("/api", api_router(state.clone()))
+        .nest("/debug", debug_router(state.clone()))
+        .layer(middleware::from_fn(helper::basic_auth_middleware))
+        // Registered after the auth layer so health checks never need credentials.
+        .route("/health", get(health))
         .layer(
             TraceLayer::new_for_http()
                 .make_span_with(DefaultMakeSpan::new().level(Level::INFO))
@@ -111,3 +155,10 @@ async fn shutdown_signal() {
 async fn health() -> impl IntoResponse {
     Json(json!({ "health": "UP" }))
 }
+
+async fn favicon() -> impl IntoResponse {
+    (
+        StatusCode::NO_CONTENT,
+        TypedHeader(CacheControl::new().with_max_age(Duration::from_secs(60 * 60 * 24))),
+    )
+}
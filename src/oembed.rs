@@ -1,9 +1,12 @@
-use std::env;
-
-use axum::{extract::Query, Json};
+use axum::{
+    extract::{Host, Query},
+    Json,
+};
 use serde::{Deserialize, Serialize};
 use urlencoding::encode;
 
+use crate::helper::{author_url, provider_name, provider_url, request_host};
+
 #[derive(Deserialize)]
 pub struct EmbedRequest {
     #[serde(rename = "n")]
@@ -24,14 +27,14 @@ pub struct EmbedResponse {
 }
 
 impl EmbedResponse {
-    fn new(author_name: String, author_url: String) -> Self {
+    fn new(author_name: String, author_url: String, host: &str) -> Self {
         Self {
             version: "1.0",
             embed_type: "rich",
             author_name,
             author_url,
-            provider_name: env::var("PROVIDER_NAME").unwrap_or_else(|_| String::from("phixiv")),
-            provider_url: env::var("PROVIDER_URL").unwrap_or_else(|_| String::from("https://github.com/HazelTheWitch/phixiv")),
+            provider_name: provider_name(host),
+            provider_url: provider_url(host),
         }
     }
 }
@@ -41,16 +44,21 @@ pub async fn oembed_handler(
         author_name,
         author_id,
     }): Query<EmbedRequest>,
+    host: Option<Host>,
 ) -> Json<EmbedResponse> {
+    let host = request_host(host);
+
     if let Some(author_id) = author_id {
         Json(EmbedResponse::new(
             author_name,
-            format!("https://www.pixiv.net/users/{}", encode(&author_id)),
+            author_url(&encode(&author_id)),
+            &host,
         ))
     } else {
         Json(EmbedResponse::new(
             author_name,
             String::from("https://www.pixiv.net/"),
+            &host,
         ))
     }
 }
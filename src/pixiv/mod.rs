@@ -1,18 +1,49 @@
-use std::{env, collections::HashMap};
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    env, fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
 
+use anyhow::Context;
 use askama::Template;
+use chrono::{
+    format::{Item, StrftimeItems},
+    DateTime,
+};
+use chrono_tz::Tz;
+use futures_util::{
+    future::{BoxFuture, Shared},
+    FutureExt,
+};
+use http::{
+    header::{ACCEPT, REFERER, RETRY_AFTER},
+    HeaderMap, HeaderValue, StatusCode,
+};
 use itertools::Itertools;
-use reqwest::Client;
+use reqwest::{Client, Response};
 use serde::{Deserialize, Serialize};
 
-use crate::helper;
+use crate::{
+    helper::{self, StatusError},
+    report,
+};
 
-use self::model::{AjaxResponse, AppReponse};
+use self::model::{
+    AjaxResponse, AjaxStatus, AppReponse, CommentsResponse, PagesResponse, UgoiraMetaResponse,
+};
 
 mod model;
 
 const ILLUST_URL: &str = "https://app-api.pixiv.net/v1/illust/detail";
 
+/// Base delay before retrying a rate limited request, doubled on each attempt.
+const RATE_LIMIT_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Upper bound on how long a single retry will wait, regardless of `Retry-After`.
+const RATE_LIMIT_MAX_DELAY: Duration = Duration::from_secs(10);
+
 #[derive(Deserialize)]
 pub struct RawArtworkPath {
     pub language: Option<String>,
@@ -30,14 +61,42 @@ impl TryFrom<RawArtworkPath> for ArtworkPath {
     type Error = anyhow::Error;
 
     fn try_from(value: RawArtworkPath) -> Result<Self, Self::Error> {
+        let language = value.language.map(|language| language.to_lowercase());
+
+        if let Some(language) = &language {
+            let is_language = !language.is_empty()
+                && language
+                    .chars()
+                    .all(|c| c.is_ascii_alphabetic() || c == '_');
+
+            if !is_language {
+                anyhow::bail!(StatusError::new(
+                    StatusCode::NOT_FOUND,
+                    format!("{language} is not a language"),
+                ));
+            }
+        }
+
+        if !value.id.trim().starts_with(|c: char| c.is_ascii_digit()) {
+            anyhow::bail!(StatusError::new(
+                StatusCode::NOT_FOUND,
+                format!("{} is not an artwork id", value.id),
+            ));
+        }
+
         let image_index = match value.image_index {
-            Some(index) => Some(index.parse()?),
+            Some(index) => Some(index.parse().map_err(|_| {
+                StatusError::new(
+                    StatusCode::NOT_FOUND,
+                    format!("{index} is not an image index"),
+                )
+            })?),
             None => None,
         };
 
         Ok(Self {
-            language: value.language,
-            id: value.id,
+            language,
+            id: value.id.trim().to_string(),
             image_index,
         })
     }
@@ -47,13 +106,22 @@ impl TryFrom<RawArtworkPath> for ArtworkPath {
 #[template(path = "artwork.html")]
 pub struct ArtworkTemplate {
     pub image_proxy_url: String,
+    pub gallery_urls: Vec<String>,
     pub title: String,
     pub description: String,
     pub author_name: String,
     pub author_id: String,
     pub url: String,
     pub alt_text: String,
+    pub published_time: String,
+    pub author_url: String,
     pub host: String,
+    pub scheme: String,
+    pub site_name: String,
+    pub card_type: String,
+    pub json_ld: Option<String>,
+    pub adult_rating: bool,
+    pub app_link: Option<String>,
 }
 
 #[derive(Debug, Serialize, Template)]
@@ -66,21 +134,296 @@ pub struct UgoiraTemplate {
     pub author_id: String,
     pub url: String,
     pub alt_text: String,
+    pub published_time: String,
+    pub author_url: String,
     pub host: String,
+    pub scheme: String,
+    pub site_name: String,
+    pub width: u32,
+    pub height: u32,
+    pub adult_rating: bool,
 }
 
-#[derive(Serialize)]
+#[derive(Clone, Serialize, Deserialize)]
 /// Representing a listing of artworks, uniquely determined by language and illust_id
 pub struct ArtworkListing {
     pub image_proxy_urls: Vec<String>,
+    /// The same images as `image_proxy_urls`, but pointing directly at pximg.
+    #[serde(skip)]
+    pub raw_image_urls: Vec<String>,
     pub title: String,
+    /// Title of the series the artwork belongs to, if any.
+    pub series_title: Option<String>,
     pub ai_generated: bool,
+    /// Pixiv's raw AI classification, 0 unknown, 1 not AI, 2 AI generated.
+    pub ai_type: u8,
     pub description: String,
     pub tags: Vec<String>,
     pub url: String,
     pub author_name: String,
     pub author_id: String,
     pub is_ugoira: bool,
+    pub is_manga: bool,
+    /// Number of pages pixiv reports for the artwork, before `MAX_PAGES` is applied.
+    pub page_count: usize,
+    /// Width and height of each page, empty unless `COVER_SELECTION=largest`.
+    pub page_dimensions: Vec<[u32; 2]>,
+    /// Proxied original of each page, if pixiv reported one.
+    pub original_proxy_urls: Vec<Option<String>>,
+    /// File extension of each page's original image, if pixiv reported one.
+    pub original_extensions: Vec<Option<String>>,
+    pub width: u32,
+    pub height: u32,
+    pub x_restrict: u8,
+    pub create_date: String,
+    pub upload_date: String,
+    pub top_comment: Option<String>,
+    /// Animation details, only filled in by `/api/info` for ugoira.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ugoira_meta: Option<UgoiraMeta>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+/// Frame count, total duration and frame size of an ugoira
+pub struct UgoiraMeta {
+    pub frame_count: usize,
+    pub duration_ms: u64,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Translations tried in order for a tag, the original tag is used when none exist.
+///
+/// `LANGUAGE_FALLBACK_CHAIN` replaces the built in fallbacks, `jp` in the chain stops at the original tag.
+fn translation_fallbacks(language: &str) -> Vec<String> {
+    let chain = helper::language_fallback_chain();
+
+    if !chain.is_empty() {
+        return std::iter::once(language.to_string())
+            .chain(chain)
+            .unique()
+            .take_while(|language| language != "jp")
+            .collect();
+    }
+
+    let fallbacks: Vec<&str> = match language {
+        "jp" => vec![],
+        "en" => vec!["en"],
+        "zh_tw" => vec!["zh_tw", "zh", "en"],
+        language => vec![language, "en"],
+    };
+
+    fallbacks.into_iter().map(String::from).collect()
+}
+
+/// Resize prefix from `THUMBNAIL_SIZE`, in pixiv's `{width}x{height}` or `{width}x{height}_{quality}` form.
+fn thumbnail_size() -> Option<String> {
+    let size = env::var("THUMBNAIL_SIZE")
+        .ok()
+        .filter(|size| !size.is_empty())?;
+
+    let (dimensions, quality) = match size.split_once('_') {
+        Some((dimensions, quality)) => (dimensions, Some(quality)),
+        None => (size.as_str(), None),
+    };
+
+    let is_number = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+
+    let valid = dimensions
+        .split_once('x')
+        .is_some_and(|(width, height)| is_number(width) && is_number(height))
+        && quality.is_none_or(is_number);
+
+    if !valid {
+        tracing::warn!("Ignoring invalid THUMBNAIL_SIZE {size}");
+        return None;
+    }
+
+    Some(size)
+}
+
+/// Whether to embed original images instead of masters, with `SMART_QUALITY` originals are used
+/// when both dimensions are within `ORIGINAL_MAX_DIMENSION`.
+fn smart_quality_original(width: u32, height: u32) -> bool {
+    let smart_quality =
+        env::var("SMART_QUALITY").unwrap_or_else(|_| String::from("false")) == "true";
+
+    let max_dimension = helper::env_or("ORIGINAL_MAX_DIMENSION", 2048);
+
+    smart_quality && width <= max_dimension && height <= max_dimension
+}
+
+/// Path of an image on pximg, resized to `THUMBNAIL_SIZE` when configured.
+fn image_path(url: &url::Url) -> String {
+    let path = url.path();
+
+    match (thumbnail_size(), path.find("/img-master/")) {
+        (Some(size), Some(start)) => format!("/c/{size}{}", &path[start..]),
+        _ => path.to_string(),
+    }
+}
+
+/// Default `DISPLAY_DATE_FORMAT`, such as `2025-11-09 14:30 JST`.
+const DISPLAY_DATE_FORMAT: &str = "%Y-%m-%d %H:%M %Z";
+
+/// Posting date for the description, converted to `DISPLAY_TIMEZONE` and formatted with
+/// `DISPLAY_DATE_FORMAT`. Nothing is shown unless a valid timezone is configured.
+fn display_date(date: &str) -> Option<String> {
+    let timezone = env::var("DISPLAY_TIMEZONE")
+        .ok()
+        .filter(|tz| !tz.is_empty())?;
+    let Ok(timezone) = timezone.parse::<Tz>() else {
+        tracing::warn!("Unknown DISPLAY_TIMEZONE {timezone}, not showing posting dates");
+        return None;
+    };
+
+    let format = env::var("DISPLAY_DATE_FORMAT")
+        .ok()
+        .filter(|format| {
+            !format.is_empty() && !StrftimeItems::new(format).any(|item| item == Item::Error)
+        })
+        .unwrap_or_else(|| String::from(DISPLAY_DATE_FORMAT));
+
+    let date = DateTime::parse_from_rfc3339(date).ok()?;
+
+    Some(format!(
+        "Posted {}",
+        date.with_timezone(&timezone).format(&format)
+    ))
+}
+
+/// Trims a title and strips control characters, collapsing runs of whitespace into one space.
+///
+/// A lone ideographic space is kept as is since it is commonly used between words in Japanese titles.
+fn normalize_title(title: &str) -> String {
+    let mut normalized = String::with_capacity(title.len());
+
+    for c in title.chars() {
+        if c.is_whitespace() {
+            if !normalized.ends_with(char::is_whitespace) {
+                normalized.push(if c == '\u{3000}' { c } else { ' ' });
+            }
+        } else if !c.is_control() {
+            normalized.push(c);
+        }
+    }
+
+    normalized.trim().to_string()
+}
+
+/// Rewrites pixiv's `illust/{id}` and `novel/{id}` caption shorthands into links through this host.
+fn link_shorthands(description: &str, host: &str) -> String {
+    let mut result = String::with_capacity(description.len());
+    let mut rest = description;
+
+    'outer: while !rest.is_empty() {
+        for (prefix, target) in [("illust/", "artworks/"), ("novel/", "novel/show.php?id=")] {
+            let preceded_by_word = result
+                .chars()
+                .next_back()
+                .is_some_and(|c| c.is_alphanumeric() || c == '_' || c == '/');
+
+            if preceded_by_word || !rest.starts_with(prefix) {
+                continue;
+            }
+
+            let id_len = rest[prefix.len()..]
+                .chars()
+                .take_while(|c| c.is_ascii_digit())
+                .count();
+
+            if id_len > 0 {
+                let id = &rest[prefix.len()..prefix.len() + id_len];
+                result.push_str(&format!("{}://{host}/{target}{id}", helper::scheme()));
+                rest = &rest[prefix.len() + id_len..];
+                continue 'outer;
+            }
+        }
+
+        let mut chars = rest.chars();
+        result.extend(chars.next());
+        rest = chars.as_str();
+    }
+
+    result
+}
+
+/// Listings served from snapshots instead of pixiv, keyed by illust id.
+pub type PinnedArtworks = Arc<HashMap<String, ArtworkListing>>;
+
+/// Loads the snapshots listed in `PINNED_ARTWORKS`, a comma separated list of `id=path` pairs
+/// where each file holds a listing as returned by `/api/info`.
+pub fn load_pinned_artworks() -> anyhow::Result<PinnedArtworks> {
+    let mut pinned = HashMap::new();
+
+    for entry in env::var("PINNED_ARTWORKS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+    {
+        let (id, path) = entry
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("PINNED_ARTWORKS entry {entry} is not id=path"))?;
+
+        let snapshot = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read pinned artwork {id} from {path}"))?;
+        let mut listing: ArtworkListing = serde_json::from_str(&snapshot)
+            .with_context(|| format!("pinned artwork {path} is not a valid listing"))?;
+
+        if listing.image_proxy_urls.is_empty() {
+            anyhow::bail!("pinned artwork {path} has no image_proxy_urls");
+        }
+
+        // Snapshots only hold the proxied urls, the pximg ones are rebuilt from their paths.
+        listing.raw_image_urls = listing
+            .image_proxy_urls
+            .iter()
+            .map(|url| raw_image_url(url))
+            .collect::<anyhow::Result<_>>()
+            .with_context(|| format!("pinned artwork {path} has an invalid image url"))?;
+
+        pinned.insert(id.trim().to_string(), listing);
+    }
+
+    if !pinned.is_empty() {
+        tracing::info!("Loaded {} pinned artworks", pinned.len());
+    }
+
+    Ok(Arc::new(pinned))
+}
+
+/// Pximg url of a proxied image url, dropping the proxy prefix from its path.
+fn raw_image_url(proxy_url: &str) -> anyhow::Result<String> {
+    let url = url::Url::parse(proxy_url)?;
+    let (_, path) = url
+        .path()
+        .trim_start_matches('/')
+        .split_once('/')
+        .ok_or_else(|| anyhow::anyhow!("{proxy_url} is not a proxied image url"))?;
+
+    Ok(format!("{}{path}", helper::pximg_base()))
+}
+
+/// In-flight listing requests, keyed by language, illust id and host, shared between concurrent callers.
+pub type ListingFlights = Arc<
+    Mutex<HashMap<String, Shared<BoxFuture<'static, Result<ArtworkListing, Arc<anyhow::Error>>>>>>,
+>;
+
+/// Error of a listing request shared by several callers.
+#[derive(Debug)]
+struct SharedError(Arc<anyhow::Error>);
+
+impl fmt::Display for SharedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#}", self.0)
+    }
+}
+
+impl std::error::Error for SharedError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.0.as_ref().as_ref())
+    }
 }
 
 async fn app_request(
@@ -93,34 +436,322 @@ async fn app_request(
     app_headers.append("Host", "app-api.pixiv.net".parse()?);
     app_headers.append("Authorization", format!("Bearer {access_token}").parse()?);
 
-    Ok(client
+    let response = client
         .get(ILLUST_URL)
         .headers(app_headers)
         .query(&app_params)
         .send()
-        .await?
-        .json()
-        .await?)
+        .await?;
+
+    match response.status() {
+        StatusCode::NOT_FOUND => anyhow::bail!(StatusError::new(
+            StatusCode::NOT_FOUND,
+            format!("artwork {illust_id} does not exist"),
+        )),
+        StatusCode::FORBIDDEN => anyhow::bail!(StatusError::new(
+            StatusCode::FORBIDDEN,
+            format!("artwork {illust_id} is not public"),
+        )),
+        _ => {}
+    }
+
+    Ok(response.error_for_status()?.json().await?)
+}
+
+/// Delay before the next attempt, honouring `Retry-After` when pixiv sends one.
+fn rate_limit_delay(response: &Response, attempt: u32) -> Duration {
+    let backoff = response
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or_else(|| RATE_LIMIT_BACKOFF.saturating_mul(2u32.saturating_pow(attempt)))
+        // Clamped before the jitter is added, a huge Retry-After would otherwise overflow.
+        .min(RATE_LIMIT_MAX_DELAY);
+
+    let jitter_range = backoff.as_millis().max(1) as u64 / 2 + 1;
+    let jitter = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() as u64 % jitter_range)
+        .unwrap_or(0);
+
+    backoff
+        .saturating_add(Duration::from_millis(jitter))
+        .min(RATE_LIMIT_MAX_DELAY)
+}
+
+/// Headers sent with ajax requests, pixiv withholds data on restricted works from requests without a pixiv referer.
+fn ajax_headers() -> anyhow::Result<HeaderMap> {
+    let referer =
+        env::var("AJAX_REFERER").unwrap_or_else(|_| String::from("https://www.pixiv.net/"));
+
+    let mut headers = HeaderMap::with_capacity(2);
+    headers.insert(REFERER, referer.parse()?);
+    headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
+
+    Ok(headers)
 }
 
 async fn ajax_request(
     illust_id: &String,
-    language: &Option<String>,
+    language: &str,
     client: &Client,
 ) -> anyhow::Result<AjaxResponse> {
-    Ok(client
+    let retries = helper::env_or("RATE_LIMIT_RETRIES", 3);
+
+    let url = format!(
+        "https://www.pixiv.net/ajax/illust/{}?lang={}",
+        &illust_id, language
+    );
+
+    let headers = ajax_headers()?;
+    let mut attempt = 0;
+
+    loop {
+        let response = match client.get(&url).headers(headers.clone()).send().await {
+            Ok(response) => response,
+            Err(e) => {
+                let e = e.into();
+                report::report_error(client, Some(illust_id), &e);
+                return Err(e);
+            }
+        };
+
+        if response.status() != StatusCode::TOO_MANY_REQUESTS {
+            let status = response.status();
+            let bytes = response.bytes().await?;
+
+            if let Ok(AjaxStatus {
+                error: true,
+                message,
+            }) = serde_json::from_slice(&bytes)
+            {
+                // Deleted works come back as 404, private or restricted ones as 403 or 401.
+                let status = match status {
+                    StatusCode::FORBIDDEN | StatusCode::UNAUTHORIZED => StatusCode::FORBIDDEN,
+                    _ => StatusCode::NOT_FOUND,
+                };
+
+                let message = if message.is_empty() {
+                    format!("artwork {illust_id} is not available")
+                } else {
+                    message
+                };
+
+                return Err(StatusError::new(status, message).into());
+            }
+
+            if !status.is_success() {
+                let e = anyhow::anyhow!("pixiv returned {status} for artwork {illust_id}");
+
+                // A missing artwork is routine, anything else pixiv fails with is worth a report.
+                if status != StatusCode::NOT_FOUND {
+                    report::report_error(client, Some(illust_id), &e);
+                }
+
+                return Err(e);
+            }
+
+            // Restricted works can come back without some fields, the caller falls back to the app api.
+            return serde_json::from_slice(&bytes)
+                .with_context(|| format!("ajax response for artwork {illust_id} is incomplete"));
+        }
+
+        if attempt >= retries {
+            return Err(StatusError::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                "rate limited by pixiv, try again later",
+            )
+            .into());
+        }
+
+        let delay = rate_limit_delay(&response, attempt);
+        tracing::warn!("Rate limited fetching {illust_id}, retrying in {delay:?}");
+
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// Whether an ajax failure means pixiv withheld the artwork from the web api, rather than
+/// the request itself failing.
+fn is_restricted(error: &anyhow::Error) -> bool {
+    helper::error_status(error) == StatusCode::FORBIDDEN
+        || error.chain().any(|e| e.is::<serde_json::Error>())
+}
+
+/// First text comment on the artwork when `COMMENTS_ENABLED` is set, formatted as `user: comment`.
+///
+/// Failures are logged rather than returned, a missing comment should never break an embed.
+async fn top_comment(illust_id: &String, client: &Client) -> anyhow::Result<Option<String>> {
+    let comments_enabled =
+        env::var("COMMENTS_ENABLED").unwrap_or_else(|_| String::from("false")) == "true";
+
+    if !comments_enabled {
+        return Ok(None);
+    }
+
+    let response = client
+        .get("https://www.pixiv.net/ajax/illusts/comments/roots")
+        .headers(ajax_headers().unwrap_or_default())
+        .query(&[
+            ("illust_id", illust_id.as_str()),
+            ("offset", "0"),
+            ("limit", "3"),
+        ])
+        .send()
+        .await
+        .and_then(|response| response.error_for_status());
+
+    let comments = match response {
+        Ok(response) => response.json::<CommentsResponse>().await,
+        Err(e) => Err(e),
+    };
+
+    match comments {
+        Ok(comments) => Ok(comments
+            .body
+            .comments
+            .into_iter()
+            .find(|comment| !comment.comment.is_empty())
+            .map(|comment| format!("{}: {}", comment.user_name, comment.comment))),
+        Err(e) => {
+            tracing::warn!("Failed to fetch comments for {illust_id}: {e}");
+            Ok(None)
+        }
+    }
+}
+
+/// Whether links without an index embed the page with the largest area, `COVER_SELECTION=largest`.
+fn largest_cover() -> bool {
+    env::var("COVER_SELECTION").as_deref() == Ok("largest")
+}
+
+/// Width and height of every page, fetched only when `COVER_SELECTION=largest` needs them.
+///
+/// Failures are logged rather than returned, the embed falls back to the first page.
+async fn page_dimensions(illust_id: &String, client: &Client) -> anyhow::Result<Vec<[u32; 2]>> {
+    if !largest_cover() {
+        return Ok(Vec::new());
+    }
+
+    let response = client
         .get(format!(
-            "https://www.pixiv.net/ajax/illust/{}?lang={}",
-            &illust_id,
-            &language.clone().unwrap_or_else(|| String::from("jp"))
+            "https://www.pixiv.net/ajax/illust/{illust_id}/pages"
         ))
+        .headers(ajax_headers().unwrap_or_default())
         .send()
-        .await?
-        .json()
-        .await?)
+        .await
+        .and_then(|response| response.error_for_status());
+
+    let pages = match response {
+        Ok(response) => response.json::<PagesResponse>().await,
+        Err(e) => Err(e),
+    };
+
+    match pages {
+        Ok(pages) => Ok(pages
+            .body
+            .into_iter()
+            .map(|page| [page.width, page.height])
+            .collect()),
+        Err(e) => {
+            tracing::warn!("Failed to fetch pages for {illust_id}: {e}");
+            Ok(Vec::new())
+        }
+    }
 }
 
 impl ArtworkListing {
+    /// Fetches the animation details of an ugoira listing, static artworks have none.
+    pub async fn ugoira_meta(
+        &self,
+        illust_id: &str,
+        client: &Client,
+    ) -> anyhow::Result<Option<UgoiraMeta>> {
+        if !self.is_ugoira {
+            return Ok(None);
+        }
+
+        let illust_id: String = illust_id
+            .trim()
+            .chars()
+            .take_while(|c| c.is_ascii_digit())
+            .collect();
+
+        let meta: UgoiraMetaResponse = client
+            .get(format!(
+                "https://www.pixiv.net/ajax/illust/{illust_id}/ugoira_meta"
+            ))
+            .headers(ajax_headers()?)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(Some(UgoiraMeta {
+            frame_count: meta.body.frames.len(),
+            duration_ms: meta.body.frames.iter().map(|frame| frame.delay).sum(),
+            width: self.width,
+            height: self.height,
+        }))
+    }
+
+    /// Like [`ArtworkListing::get_listing`], but concurrent calls for the same listing share a single upstream request.
+    pub async fn get_listing_coalesced(
+        flights: &ListingFlights,
+        pinned: &PinnedArtworks,
+        language: Option<String>,
+        illust_id: String,
+        access_token: String,
+        host: String,
+        client: Client,
+    ) -> anyhow::Result<Self> {
+        if let Some(listing) = pinned.get(illust_id.trim()) {
+            return Ok(listing.clone());
+        }
+
+        let language = language
+            .map(|language| language.trim().to_lowercase())
+            .unwrap_or_else(helper::default_language);
+        let illust_id = illust_id.trim().to_string();
+        let key = format!("{language}_{illust_id}_{host}");
+
+        let flight = flights
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_insert_with(|| {
+                let flights = flights.clone();
+
+                // Run the request as its own task, so it finishes and clears its entry
+                // even when every caller has gone away.
+                let request = tokio::spawn(async move {
+                    let result =
+                        Self::get_listing(Some(language), illust_id, &access_token, &host, &client)
+                            .await
+                            .map_err(Arc::new);
+
+                    flights.lock().unwrap().remove(&key);
+
+                    result
+                });
+
+                async move {
+                    request
+                        .await
+                        .unwrap_or_else(|e| Err(Arc::new(anyhow::Error::new(e))))
+                }
+                .boxed()
+                .shared()
+            })
+            .clone();
+
+        Ok(flight.await.map_err(SharedError)?)
+    }
+
     pub async fn get_listing(
         language: Option<String>,
         illust_id: String,
@@ -128,89 +759,341 @@ impl ArtworkListing {
         host: &str,
         client: &Client,
     ) -> anyhow::Result<Self> {
-        let clean_illust_id = illust_id.chars().take_while(|c| c.is_numeric()).collect::<String>();
-        let (app_response, ajax_response) = tokio::try_join!(
+        let language = language.unwrap_or_else(helper::default_language);
+        let clean_illust_id = illust_id
+            .chars()
+            .take_while(|c| c.is_numeric())
+            .collect::<String>();
+        let (mut app_response, ajax_response, top_comment, mut page_dimensions) = tokio::try_join!(
             app_request(&clean_illust_id, access_token, client),
-            ajax_request(&clean_illust_id, &language, client),
+            async { anyhow::Ok(ajax_request(&clean_illust_id, &language, client).await) },
+            top_comment(&clean_illust_id, client),
+            page_dimensions(&clean_illust_id, client),
         )?;
 
+        // The app api already described the artwork, use that when the ajax api restricts it.
+        // Other failures, such as rate limiting, are passed on as they are.
+        let ajax_response = match ajax_response {
+            Ok(ajax_response) => ajax_response,
+            Err(e) if is_restricted(&e) => {
+                tracing::warn!(
+                    "Ajax request for {clean_illust_id} failed, falling back to the app api: {e:#}"
+                );
+
+                AjaxResponse {
+                    body: app_response.illust.to_ajax_body(&clean_illust_id),
+                }
+            }
+            Err(e) => return Err(e),
+        };
+
+        if !app_response.illust.visible {
+            anyhow::bail!(StatusError::new(
+                StatusCode::FORBIDDEN,
+                format!("artwork {clean_illust_id} requires login or is not public yet"),
+            ));
+        }
+
         let ai_generated = app_response.illust.illust_ai_type == 2;
 
-        let tags: Vec<_> = ajax_response.body
+        let fallbacks = translation_fallbacks(&language);
+
+        let tags: Vec<_> = ajax_response
+            .body
             .tags
             .tags
             .into_iter()
             .map(|tag| {
                 format!(
                     "#{}",
-                    if let Some(language) = &language {
-                        if let Some(translation) = tag.translation {
-                            translation.get(language).unwrap_or(&tag.tag).to_string()
-                        } else {
-                            tag.tag
-                        }
-                    } else {
-                        tag.tag
-                    }
+                    tag.translation
+                        .and_then(|translation| {
+                            fallbacks
+                                .iter()
+                                .find_map(|language| translation.get(language).cloned())
+                        })
+                        .unwrap_or(tag.tag)
                 )
             })
             .collect();
 
+        let is_manga = ajax_response.body.illust_type == 1;
         let is_ugoira = ajax_response.body.illust_type == 2;
-        let ugoira_enabled = env::var("UGOIRA_ENABLED")
-            .unwrap_or_else(|_| String::from("false")) == "true";
+        // The o. subdomain always embeds originals, like sfw. it is opted into by the link.
+        // Blurred works stay on the master size, the blur route only decodes resized images.
+        let use_original = (host.starts_with("o.")
+            || smart_quality_original(ajax_response.body.width, ajax_response.body.height))
+            && !(helper::blur_nsfw() && ajax_response.body.x_restrict > 0);
+
+        let max_pages = helper::env_or("MAX_PAGES", 50usize).max(1);
+
+        app_response.illust.meta_pages.truncate(max_pages);
+        page_dimensions.truncate(max_pages);
+
+        // Ugoira frames are not separate pages on pximg, only the first frame exists as a still.
+        if is_ugoira {
+            app_response.illust.meta_pages.clear();
+        }
 
-        let image_proxy_urls = if is_ugoira && ugoira_enabled {
-            vec![format!("https://{}/i/ugoira/{}.mp4", host, clean_illust_id)]
+        let original_urls: Vec<Option<String>> = if app_response.illust.meta_pages.is_empty() {
+            vec![app_response
+                .illust
+                .meta_single_page
+                .original_image_url
+                .clone()]
+        } else {
+            app_response
+                .illust
+                .meta_pages
+                .iter()
+                .map(|mp| mp.image_urls.original.clone())
+                .collect()
+        };
+
+        let original_proxy_urls = original_urls
+            .iter()
+            .map(|url| {
+                url.as_ref()
+                    .and_then(|url| url::Url::parse(url).ok())
+                    .map(|url| helper::proxy_url(host, url.path()))
+            })
+            .collect();
+
+        let original_extensions = original_urls
+            .iter()
+            .map(|url| {
+                url.as_ref()
+                    .and_then(|url| url.rsplit('/').next()?.rsplit_once('.'))
+                    .map(|(_, extension)| extension.to_lowercase())
+            })
+            .collect();
+
+        let image_paths = if is_ugoira && helper::ugoira_enabled() {
+            vec![format!("/ugoira/{clean_illust_id}.mp4")]
         } else if app_response.illust.meta_pages.is_empty() {
-            let url = url::Url::parse(&app_response.illust.image_urls.large)?;
+            let url = match app_response.illust.meta_single_page.original_image_url {
+                Some(original) if use_original => original,
+                _ => app_response.illust.image_urls.large,
+            };
+            let url = url::Url::parse(&url)?;
 
-            vec![format!("https://{}/i{}", host, url.path())]
+            vec![image_path(&url)]
         } else {
-            app_response.illust
+            app_response
+                .illust
                 .meta_pages
                 .into_iter()
                 .map(|mp| {
-                    let url = url::Url::parse(&mp.image_urls.large)?;
+                    let url = match mp.image_urls.original {
+                        Some(original) if use_original => original,
+                        _ => mp.image_urls.large,
+                    };
+                    let url = url::Url::parse(&url)?;
 
-                    Ok(format!("https://{}/i{}", host, url.path()))
+                    Ok(image_path(&url))
                 })
                 .collect::<anyhow::Result<Vec<String>>>()?
         };
 
+        // Page urls are derived from the app api, a count that disagrees with the ajax api means pixiv changed something.
+        let expected_pages = ajax_response.body.page_count.min(max_pages);
+        if !is_ugoira && image_paths.len() != expected_pages {
+            tracing::warn!(
+                "Artwork {clean_illust_id} has {expected_pages} pages but {} image urls were derived",
+                image_paths.len()
+            );
+        }
+
+        let image_proxy_urls = image_paths
+            .iter()
+            .map(|path| helper::proxy_url(host, path))
+            .collect();
+
+        let raw_image_urls = image_paths
+            .iter()
+            .map(|path| format!("{}{}", helper::pximg_base(), path.trim_start_matches('/')))
+            .collect();
+
         Ok(Self {
             image_proxy_urls,
-            title: ajax_response.body.title,
+            raw_image_urls,
+            title: normalize_title(&ajax_response.body.title),
+            series_title: ajax_response
+                .body
+                .series_nav_data
+                .map(|series| normalize_title(&series.title))
+                .filter(|title| !title.is_empty()),
             ai_generated,
-            description: ajax_response.body.description,
+            ai_type: app_response.illust.illust_ai_type,
+            description: link_shorthands(&ajax_response.body.description, host),
             tags,
             url: ajax_response.body.extra_data.meta.canonical,
             author_name: ajax_response.body.author_name,
             author_id: ajax_response.body.author_id,
             is_ugoira,
+            is_manga,
+            page_count: ajax_response.body.page_count,
+            page_dimensions,
+            original_proxy_urls,
+            original_extensions,
+            width: ajax_response.body.width,
+            height: ajax_response.body.height,
+            x_restrict: ajax_response.body.x_restrict,
+            create_date: ajax_response.body.create_date,
+            upload_date: ajax_response.body.upload_date,
+            top_comment,
+            ugoira_meta: None,
         })
     }
 
-    pub fn to_template(self, image_index: Option<usize>, host: String) -> anyhow::Result<String> {
+    /// With `STRICT_INDEX=true`, rejects index 0 or an index past the last page with a 404
+    /// instead of clamping it to the nearest page.
+    pub fn check_index(&self, image_index: Option<usize>) -> Result<(), StatusError> {
+        let strict = env::var("STRICT_INDEX").unwrap_or_else(|_| String::from("false")) == "true";
+        let pages = self.page_count.max(self.image_proxy_urls.len());
+
+        match image_index {
+            Some(index) if strict && (index == 0 || index > pages) => Err(StatusError::new(
+                StatusCode::NOT_FOUND,
+                format!("page {index} does not exist, the artwork has {pages} pages"),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Derives the template fields for embedding the page at `image_index`.
+    pub fn embed_fields(
+        &self,
+        image_index: Option<usize>,
+        host: String,
+        card_type: &str,
+    ) -> EmbedFields {
+        // Links without an index show the first page, the last with DEFAULT_INDEX=last, or the
+        // largest with COVER_SELECTION=largest when the page sizes are known.
+        let sizes_known = self.page_dimensions.len() == self.image_proxy_urls.len();
+
+        let largest_page = (largest_cover() && sizes_known)
+            .then(|| {
+                self.page_dimensions
+                    .iter()
+                    .enumerate()
+                    .max_by_key(|(i, [width, height])| {
+                        (u64::from(*width) * u64::from(*height), Reverse(*i))
+                    })
+                    .map(|(i, _)| i + 1)
+            })
+            .flatten();
+
+        let default_index = match (largest_page, env::var("DEFAULT_INDEX").as_deref()) {
+            (Some(page), _) => page,
+            (None, Ok("last")) => self.image_proxy_urls.len(),
+            _ => 1,
+        };
+
         let index = image_index
-            .unwrap_or(1)
+            .unwrap_or(default_index)
             .min(self.image_proxy_urls.len())
             .saturating_sub(1);
 
-        let image_proxy_url = self.image_proxy_urls[index].clone();
+        // With UGOIRA_ENABLED=false the image is the first frame, embedded like any other still.
+        let is_video = self.is_ugoira && helper::ugoira_enabled();
 
-        let tag_string = Itertools::intersperse_with(self.tags.into_iter(), || String::from(", "))
-            .collect::<String>();
+        let blurred = helper::blur_nsfw() && self.x_restrict > 0 && !is_video;
+
+        let embed_url = |url: &String| {
+            if blurred {
+                url.replacen(
+                    &helper::proxy_url(&host, "/"),
+                    &helper::proxy_url(&host, "/blur/"),
+                    1,
+                )
+            } else {
+                url.clone()
+            }
+        };
+
+        // NSFW_MODE=no-image keeps the text of sensitive works but swaps the image for
+        // NSFW_PLACEHOLDER_URL, or drops it when no placeholder is configured.
+        let hide_image = self.x_restrict > 0 && env::var("NSFW_MODE").as_deref() == Ok("no-image");
+
+        let image_proxy_url = if hide_image {
+            env::var("NSFW_PLACEHOLDER_URL").unwrap_or_default()
+        } else {
+            embed_url(&self.image_proxy_urls[index])
+        };
+
+        // The remaining pages, in order, for clients that build a gallery from several og:image tags.
+        let multi_og_image =
+            env::var("MULTI_OG_IMAGE").unwrap_or_else(|_| String::from("false")) == "true";
+        let max_og_images = helper::env_or("MULTI_OG_IMAGE_MAX", 4usize);
+
+        let gallery_urls = if multi_og_image && !is_video && !hide_image {
+            self.image_proxy_urls
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| *i != index)
+                .take(max_og_images.saturating_sub(1))
+                .map(|(_, url)| embed_url(url))
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        // Links to the full size page alongside a resized embed image, ugoira have no original.
+        // A blurred work keeps its original out of the embed entirely.
+        let original_url = self
+            .original_proxy_urls
+            .get(index)
+            .cloned()
+            .flatten()
+            .filter(|original| {
+                !is_video && !hide_image && !blurred && *original != self.image_proxy_urls[index]
+            });
+
+        // Long tag lists overflow embeds, anything past MAX_TAGS is summarised as a count.
+        let max_tags = helper::env_or("MAX_TAGS", 15usize);
+
+        let mut tag_string = self.tags.iter().take(max_tags).join(", ");
+
+        if self.tags.len() > max_tags {
+            let more = format!("+{} more", self.tags.len() - max_tags);
+            tag_string = if tag_string.is_empty() {
+                more
+            } else {
+                format!("{tag_string}, {more}")
+            };
+        }
+
+        let page_count = match self.page_count {
+            1 => String::from("Manga, 1 page"),
+            n => format!("Manga, {n} pages"),
+        };
+
+        // Which AI labels to show, AI_BADGE=always|never|generated-only.
+        let ai_badge = env::var("AI_BADGE").unwrap_or_else(|_| String::from("always"));
 
         let description = Itertools::intersperse_with(
             [
-                String::from(if self.ai_generated {
-                    "AI Generated\n"
-                } else {
-                    ""
+                String::from(match (self.ai_type, ai_badge.as_str()) {
+                    (0 | 1, _) | (_, "never") => "",
+                    (2, _) => "AI Generated\n",
+                    (_, "generated-only") => "",
+                    _ => "AI Assisted\n",
                 }),
-                self.description,
+                if self.is_manga {
+                    page_count
+                } else {
+                    String::new()
+                },
+                self.description.clone(),
                 tag_string.clone(),
+                original_url
+                    .as_ref()
+                    .map(|original| format!("Original: {original}"))
+                    .unwrap_or_default(),
+                display_date(&self.create_date).unwrap_or_default(),
+                self.top_comment.clone().unwrap_or_default(),
+                env::var("ATTRIBUTION_TEXT").unwrap_or_default(),
             ]
             .into_iter()
             .filter(|s| !s.is_empty()),
@@ -218,28 +1101,216 @@ impl ArtworkListing {
         )
         .collect::<String>();
 
-        if self.is_ugoira {
+        // Some clients show a blank line for an empty description, EMPTY_DESC_FALLBACK fills it in.
+        let empty_fallback =
+            env::var("EMPTY_DESC_FALLBACK").unwrap_or_else(|_| String::from("false")) == "true";
+
+        let description = if description.is_empty() && empty_fallback {
+            format!("By {} · View on pixiv", self.author_name)
+        } else {
+            description
+        };
+
+        let alt_text = Itertools::intersperse_with(
+            [self.title.clone(), tag_string]
+                .into_iter()
+                .filter(|s| !s.is_empty()),
+            || String::from(" - "),
+        )
+        .collect::<String>();
+
+        let jsonld_enabled =
+            env::var("JSONLD_ENABLED").unwrap_or_else(|_| String::from("false")) == "true";
+
+        let json_ld = (jsonld_enabled && !is_video).then(|| {
+            serde_json::json!({
+                "@context": "https://schema.org",
+                "@type": "ImageObject",
+                "name": self.title,
+                "url": self.url,
+                "contentUrl": image_proxy_url,
+                "dateCreated": self.create_date,
+                "author": {
+                    "@type": "Person",
+                    "name": self.author_name,
+                    "url": helper::author_url(&self.author_id),
+                },
+            })
+            .to_string()
+            // Keep the payload from closing the surrounding script element.
+            .replace('<', "\\u003c")
+        });
+
+        // Sensitivity hint honoured by Slack and content filters, opt-in with EMIT_RATING.
+        let adult_rating = self.x_restrict > 0
+            && env::var("EMIT_RATING").unwrap_or_else(|_| String::from("false")) == "true";
+
+        let series_prefix =
+            env::var("SERIES_TITLE_PREFIX").unwrap_or_else(|_| String::from("false")) == "true";
+
+        let title = match &self.series_title {
+            Some(series_title) if series_prefix => format!("{series_title} — {}", self.title),
+            _ => self.title.clone(),
+        };
+
+        // Deep link into the pixiv app for mobile clients, the page itself is the web fallback.
+        let app_links = env::var("APP_LINKS").unwrap_or_else(|_| String::from("false")) == "true";
+
+        let app_link = self
+            .url
+            .rsplit('/')
+            .next()
+            .filter(|id| app_links && !id.is_empty() && id.chars().all(|c| c.is_ascii_digit()))
+            .map(|id| format!("pixiv://illusts/{id}"));
+
+        EmbedFields {
+            index,
+            image_proxy_url,
+            gallery_urls,
+            original_url,
+            title,
+            description,
+            author_name: self.author_name.clone(),
+            author_id: self.author_id.clone(),
+            url: self.url.clone(),
+            alt_text,
+            published_time: self.create_date.clone(),
+            author_url: helper::author_url(&self.author_id),
+            site_name: helper::provider_name(&host),
+            host,
+            scheme: helper::scheme(),
+            card_type: card_type.to_string(),
+            json_ld,
+            width: self.width,
+            height: self.height,
+            // Without an image there is no video either, the work embeds as a plain card.
+            is_video: is_video && !hide_image,
+            adult_rating,
+            app_link,
+        }
+    }
+
+    pub fn to_template(
+        self,
+        image_index: Option<usize>,
+        host: String,
+        card_type: &str,
+    ) -> anyhow::Result<String> {
+        let fields = self.embed_fields(image_index, host, card_type);
+
+        match fields.clone().render() {
+            Ok(html) => Ok(html),
+            Err(e) => {
+                tracing::error!("Failed to render embed for {}: {e:#}", fields.url);
+                Ok(fields.render_text())
+            }
+        }
+    }
+}
+
+/// Escapes `text` for use in html text and double quoted attributes.
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#x27;"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+#[derive(Clone, Debug, Serialize)]
+/// Everything an embed template needs, derived from a listing for a single page
+pub struct EmbedFields {
+    pub index: usize,
+    pub image_proxy_url: String,
+    pub gallery_urls: Vec<String>,
+    pub original_url: Option<String>,
+    pub title: String,
+    pub description: String,
+    pub author_name: String,
+    pub author_id: String,
+    pub url: String,
+    pub alt_text: String,
+    pub published_time: String,
+    pub author_url: String,
+    pub host: String,
+    pub scheme: String,
+    pub site_name: String,
+    pub card_type: String,
+    pub json_ld: Option<String>,
+    pub width: u32,
+    pub height: u32,
+    /// Only set when `image_proxy_url` is the mp4, ugoira.html announces it as a video stream.
+    pub is_video: bool,
+    pub adult_rating: bool,
+    pub app_link: Option<String>,
+}
+
+impl EmbedFields {
+    /// Bare title, description and link, for when the templates fail to render.
+    pub fn render_text(&self) -> String {
+        let title = escape_html(&self.title);
+        let description = escape_html(&self.description);
+        let url = escape_html(&self.url);
+
+        format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n\
+             <meta charset=\"UTF-8\">\n\
+             <title>{title}</title>\n\
+             <meta property=\"og:title\" content=\"{title}\" />\n\
+             <meta property=\"og:description\" content=\"{description}\" />\n\
+             <meta property=\"og:url\" content=\"{url}\" />\n\
+             </head>\n<body>\n<a href=\"{url}\">{title}</a>\n</body>\n</html>\n"
+        )
+    }
+
+    pub fn render(self) -> anyhow::Result<String> {
+        if self.is_video {
             let template = UgoiraTemplate {
-                image_proxy_url,
+                image_proxy_url: self.image_proxy_url,
                 title: self.title,
-                description,
+                description: self.description,
                 author_name: self.author_name,
                 author_id: self.author_id,
                 url: self.url,
-                alt_text: tag_string,
-                host,
+                alt_text: self.alt_text,
+                published_time: self.published_time,
+                author_url: self.author_url,
+                host: self.host,
+                scheme: self.scheme,
+                site_name: self.site_name,
+                width: self.width,
+                height: self.height,
+                adult_rating: self.adult_rating,
             };
             return Ok(template.render()?);
         }
         let template = ArtworkTemplate {
-            image_proxy_url,
+            image_proxy_url: self.image_proxy_url,
+            gallery_urls: self.gallery_urls,
             title: self.title,
-            description,
+            description: self.description,
             author_name: self.author_name,
             author_id: self.author_id,
             url: self.url,
-            alt_text: tag_string,
-            host,
+            alt_text: self.alt_text,
+            published_time: self.published_time,
+            author_url: self.author_url,
+            host: self.host,
+            scheme: self.scheme,
+            site_name: self.site_name,
+            card_type: self.card_type,
+            json_ld: self.json_ld,
+            adult_rating: self.adult_rating,
+            app_link: self.app_link,
         };
         Ok(template.render()?)
     }
@@ -10,8 +10,100 @@ pub(super) struct AppReponse {
 #[derive(Debug, Deserialize)]
 pub(super) struct IllustrationResponse {
     pub image_urls: ImageUrls,
+    pub meta_single_page: MetaSinglePage,
     pub meta_pages: Vec<MetaPage>,
     pub illust_ai_type: u8,
+    #[serde(default = "visible_default")]
+    pub visible: bool,
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub caption: String,
+    #[serde(default)]
+    pub user: AppUser,
+    #[serde(default)]
+    pub tags: Vec<AppTag>,
+    #[serde(rename = "type", default)]
+    pub illust_type: String,
+    #[serde(default)]
+    pub width: u32,
+    #[serde(default)]
+    pub height: u32,
+    #[serde(default)]
+    pub x_restrict: u8,
+    #[serde(default)]
+    pub create_date: String,
+    #[serde(default = "page_count_default")]
+    pub page_count: usize,
+}
+
+impl IllustrationResponse {
+    /// The artwork in the shape of the ajax api, for when that api refuses the request.
+    ///
+    /// The app api only has English tag translations and no separate upload date or series data.
+    pub fn to_ajax_body(&self, illust_id: &str) -> AjaxBody {
+        AjaxBody {
+            title: self.title.clone(),
+            description: self.caption.clone(),
+            tags: Tags {
+                tags: self
+                    .tags
+                    .iter()
+                    .map(|tag| Tag {
+                        tag: tag.name.clone(),
+                        translation: tag
+                            .translated_name
+                            .clone()
+                            .map(|translation| HashMap::from([(String::from("en"), translation)])),
+                    })
+                    .collect(),
+            },
+            author_id: self.user.id.to_string(),
+            author_name: self.user.name.clone(),
+            extra_data: AjaxExtraData {
+                meta: AjaxMeta {
+                    canonical: format!("https://www.pixiv.net/artworks/{illust_id}"),
+                },
+            },
+            illust_type: match self.illust_type.as_str() {
+                "manga" => 1,
+                "ugoira" => 2,
+                _ => 0,
+            },
+            width: self.width,
+            height: self.height,
+            x_restrict: self.x_restrict,
+            create_date: self.create_date.clone(),
+            upload_date: self.create_date.clone(),
+            page_count: self.page_count,
+            series_nav_data: None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub(super) struct AppUser {
+    pub id: u64,
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct AppTag {
+    pub name: String,
+    pub translated_name: Option<String>,
+}
+
+fn visible_default() -> bool {
+    true
+}
+
+fn page_count_default() -> usize {
+    1
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct MetaSinglePage {
+    pub original_image_url: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -22,6 +114,7 @@ pub(super) struct MetaPage {
 #[derive(Debug, Deserialize)]
 pub(super) struct MetaPageImageUrls {
     pub large: String,
+    pub original: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -34,6 +127,15 @@ pub(super) struct AjaxResponse {
     pub body: AjaxBody,
 }
 
+/// Envelope of every ajax response, `body` is an empty array rather than an object when `error` is set.
+#[derive(Debug, Deserialize)]
+pub(super) struct AjaxStatus {
+    #[serde(default)]
+    pub error: bool,
+    #[serde(default)]
+    pub message: String,
+}
+
 #[derive(Debug, Deserialize)]
 pub(super) struct AjaxBody {
     pub title: String,
@@ -47,6 +149,23 @@ pub(super) struct AjaxBody {
     pub extra_data: AjaxExtraData,
     #[serde(rename = "illustType")]
     pub illust_type: u8,
+    pub width: u32,
+    pub height: u32,
+    #[serde(rename = "xRestrict")]
+    pub x_restrict: u8,
+    #[serde(rename = "createDate")]
+    pub create_date: String,
+    #[serde(rename = "uploadDate")]
+    pub upload_date: String,
+    #[serde(rename = "pageCount", default = "page_count_default")]
+    pub page_count: usize,
+    #[serde(rename = "seriesNavData")]
+    pub series_nav_data: Option<SeriesNavData>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct SeriesNavData {
+    pub title: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -69,3 +188,46 @@ pub(super) struct AjaxExtraData {
 pub(super) struct AjaxMeta {
     pub canonical: String,
 }
+
+#[derive(Debug, Deserialize)]
+pub(super) struct PagesResponse {
+    pub body: Vec<Page>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct Page {
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct UgoiraMetaResponse {
+    pub body: UgoiraMetaBody,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct UgoiraMetaBody {
+    pub frames: Vec<UgoiraFrame>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct UgoiraFrame {
+    pub delay: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct CommentsResponse {
+    pub body: CommentsBody,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct CommentsBody {
+    pub comments: Vec<Comment>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct Comment {
+    #[serde(rename = "userName")]
+    pub user_name: String,
+    pub comment: String,
+}
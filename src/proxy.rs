@@ -1,48 +1,364 @@
-use std::{sync::Arc, time::Duration, env};
+use std::{
+    env,
+    io::{self, Cursor},
+    sync::Arc,
+    time::Duration,
+};
 
 use axum::{
     body::StreamBody,
-    extract::{Path, State},
-    headers::CacheControl,
+    error_handling::HandleErrorLayer,
+    extract::{DefaultBodyLimit, Path, RawQuery, State},
+    headers::{CacheControl, ContentType},
     middleware,
-    response::IntoResponse,
+    response::{IntoResponse, Redirect, Response},
     routing::get,
-    Router, TypedHeader,
+    BoxError, Extension, Router, TypedHeader,
+};
+use bytes::Bytes;
+use futures_util::{future, Stream, StreamExt};
+use http::{header::CONTENT_TYPE, HeaderMap, HeaderValue, StatusCode};
+use image::{
+    io::{Limits, Reader},
+    ImageOutputFormat,
 };
-use tokio::sync::RwLock;
+use tokio::sync::{OwnedSemaphorePermit, RwLock, Semaphore};
+use tower::{limit::GlobalConcurrencyLimitLayer, ServiceBuilder};
 
 use crate::{
-    helper::{self, PhixivError},
+    disk_cache,
+    embed::EMBED_BODY_LIMIT,
+    helper::{self, PhixivError, StatusError},
+    report,
     state::{authorized_middleware, PhixivState},
 };
 
+/// Longest edge of a blurred preview, images are downscaled before blurring to keep it cheap.
+const BLUR_MAX_DIMENSION: u32 = 600;
+
+/// Largest image the blur route downloads, embeds only ever blur resized images.
+const BLUR_MAX_BYTES: u64 = 8 * 1024 * 1024;
+
+/// Largest width or height the blur route decodes, resized pximg images are at most 1200px.
+const BLUR_MAX_DECODE_DIMENSION: u32 = 4096;
+
+/// Paths the blur route accepts, the resized images embeds point at.
+const BLUR_PATH_PREFIXES: [&str; 2] = ["img-master/", "c/"];
+
+/// Path relative to pximg, with the origin removed from pasted absolute urls like `/i/https://i.pximg.net/img-master/...`.
+fn relative_path(path: &str) -> &str {
+    let path = path.trim_start_matches('/');
+    let path = ["https:", "http:"]
+        .iter()
+        .find_map(|scheme| path.strip_prefix(scheme))
+        .unwrap_or(path)
+        .trim_start_matches('/');
+
+    path.strip_prefix("i.pximg.net")
+        .map(|path| path.trim_start_matches('/'))
+        .unwrap_or(path)
+}
+
+async fn fetch_upstream(
+    state: &Arc<RwLock<PhixivState>>,
+    path: &str,
+    query: Option<&str>,
+) -> anyhow::Result<reqwest::Response> {
+    // Clone the client out so the state lock is not held while waiting on pximg.
+    let client = state.read().await.client.clone();
+
+    let mut url = format!("{}{}", helper::pximg_base(), relative_path(path));
+
+    // Some pximg paths, such as profile images, are versioned through the query string.
+    if let Some(query) = query.filter(|query| !query.is_empty()) {
+        url = format!("{url}?{query}");
+    }
+
+    let mut headers = helper::headers();
+    headers.append("Referer", "https://www.pixiv.net/".parse()?);
+
+    let response = client.get(&url).headers(headers).send().await?;
+
+    if let (Some(max_bytes), Some(length)) = (max_proxy_bytes(), response.content_length()) {
+        if length > max_bytes {
+            anyhow::bail!(StatusError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("image is {length} bytes, the limit is {max_bytes}"),
+            ));
+        }
+    }
+
+    Ok(response)
+}
+
+/// Largest response the proxy will pass through, configured with `MAX_PROXY_BYTES`.
+fn max_proxy_bytes() -> Option<u64> {
+    env::var("MAX_PROXY_BYTES").ok()?.parse().ok()
+}
+
+/// Body of `response`, cut off with an error once it grows past `max_bytes`.
+fn limited_body(
+    response: reqwest::Response,
+    max_bytes: u64,
+) -> impl Stream<Item = Result<Bytes, io::Error>> {
+    response.bytes_stream().scan(0u64, move |read, chunk| {
+        let chunk = chunk.map_err(io::Error::other).and_then(|chunk| {
+            *read += chunk.len() as u64;
+
+            if *read > max_bytes {
+                Err(io::Error::other(format!(
+                    "image exceeded {max_bytes} bytes"
+                )))
+            } else {
+                Ok(chunk)
+            }
+        });
+
+        future::ready(Some(chunk))
+    })
+}
+
+/// Buffers the whole body of `response`, bounded by `max_bytes`.
+async fn read_limited(response: reqwest::Response, max_bytes: u64) -> io::Result<Bytes> {
+    let mut body = std::pin::pin!(limited_body(response, max_bytes));
+    let mut bytes = Vec::new();
+
+    while let Some(chunk) = body.next().await {
+        bytes.extend_from_slice(&chunk?);
+    }
+
+    Ok(bytes.into())
+}
+
+fn cache_control() -> TypedHeader<CacheControl> {
+    TypedHeader(
+        CacheControl::new()
+            .with_max_age(Duration::from_secs(60 * 60 * 24))
+            .with_public(),
+    )
+}
+
+/// Replays the upstream `Content-Type`, a bare `Bytes` body would otherwise go out as octet-stream.
+fn content_type_header(content_type: Option<&str>) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+
+    if let Some(value) = content_type.and_then(|value| HeaderValue::from_str(value).ok()) {
+        headers.insert(CONTENT_TYPE, value);
+    }
+
+    headers
+}
+
+/// Concurrent image transfers, limited by `PROXY_MAX_CONCURRENCY`.
+#[derive(Clone)]
+struct ProxyPermits(Arc<Semaphore>);
+
+/// Keeps `permit` until `stream` is dropped, so a transfer counts against the limit until its body is sent.
+fn hold_permit<S: Stream>(stream: S, permit: OwnedSemaphorePermit) -> impl Stream<Item = S::Item> {
+    stream.map(move |item| {
+        let _permit = &permit;
+        item
+    })
+}
+
 async fn proxy_handler(
     State(state): State<Arc<RwLock<PhixivState>>>,
+    Extension(ProxyPermits(permits)): Extension<ProxyPermits>,
     Path(path): Path<String>,
-) -> Result<impl IntoResponse, PhixivError> {
-    let state = state.read().await;
+    RawQuery(query): RawQuery,
+) -> Result<Response, PhixivError> {
+    // Legacy pixiv links of the form /i/:id refer to artworks, pximg never serves bare ids.
+    if !path.is_empty() && path.chars().all(|c| c.is_ascii_digit()) {
+        return Ok(Redirect::permanent(&format!("/artworks/{path}")).into_response());
+    }
 
-    let base = env::var("PXIMG_BASE").unwrap_or_else(|_| String::from("https://i.pximg.net/"));
-    let url = format!("{base}{path}");
+    let Ok(permit) = permits.try_acquire_owned() else {
+        return Ok(overloaded().await.into_response());
+    };
 
-    let mut headers = helper::headers();
-    headers.append("Referer", "https://www.pixiv.net/".parse()?);
+    // Requests with a query string are proxied but never cached, so arbitrary queries
+    // can't push popular images out of the cache.
+    let cache_key = path.clone();
+    let cache_dir =
+        disk_cache::cache_dir().filter(|_| query.as_deref().unwrap_or_default().is_empty());
+
+    if let Some(dir) = &cache_dir {
+        if let Some(image) = disk_cache::get(dir, &cache_key).await {
+            return Ok((
+                cache_control(),
+                content_type_header(image.content_type.as_deref()),
+                image.bytes,
+            )
+                .into_response());
+        }
+    }
+
+    let response = match fetch_upstream(&state, &path, query.as_deref()).await {
+        Ok(response) => response,
+        Err(e) => {
+            if e.downcast_ref::<StatusError>().is_none() {
+                report::report_error(&state.read().await.client, None, &e);
+            }
+            return Err(e.into());
+        }
+    };
+
+    if response.status().is_server_error() {
+        report::report_error(
+            &state.read().await.client,
+            None,
+            &anyhow::anyhow!("pximg returned {} for {path}", response.status()),
+        );
+    }
+
+    if response.status().is_client_error() || response.status().is_server_error() {
+        if let Some(fallback_url) = env::var("FALLBACK_IMAGE_URL")
+            .ok()
+            .filter(|url| !url.is_empty())
+        {
+            return fallback_response(&state, &fallback_url, response.status(), permit).await;
+        }
+    }
 
-    let response = state.client.get(&url).headers(headers).send().await?;
+    let status = response.status();
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
+    let headers = content_type_header(content_type.as_deref());
+    let max_bytes = max_proxy_bytes().unwrap_or(u64::MAX);
+
+    // Only images of a known, bounded size are buffered for the cache, the rest stream through.
+    let cacheable = response
+        .content_length()
+        .is_some_and(|length| length <= disk_cache::max_entry_bytes());
+
+    if let Some(dir) = cache_dir.filter(|_| status.is_success() && cacheable) {
+        let bytes = read_limited(response, max_bytes).await?;
+
+        tokio::spawn(disk_cache::insert(
+            dir,
+            cache_key,
+            disk_cache::CachedImage {
+                content_type,
+                bytes: bytes.clone(),
+            },
+        ));
+
+        return Ok((status, cache_control(), headers, bytes).into_response());
+    }
 
     Ok((
-        response.status(),
-        TypedHeader(
-            CacheControl::new()
-                .with_max_age(Duration::from_secs(60 * 60 * 24))
-                .with_public(),
-        ),
-        StreamBody::new(response.bytes_stream()),
-    ))
+        status,
+        cache_control(),
+        headers,
+        StreamBody::new(hold_permit(limited_body(response, max_bytes), permit)),
+    )
+        .into_response())
+}
+
+/// Serves the placeholder from `fallback_url` in place of a failed upstream image.
+async fn fallback_response(
+    state: &Arc<RwLock<PhixivState>>,
+    fallback_url: &str,
+    upstream_status: StatusCode,
+    permit: OwnedSemaphorePermit,
+) -> Result<Response, PhixivError> {
+    let client = state.read().await.client.clone();
+    let fallback = client.get(fallback_url).send().await?.error_for_status()?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert("X-Upstream-Status", upstream_status.as_u16().into());
+
+    if let Some(content_type) = fallback.headers().get(CONTENT_TYPE) {
+        headers.insert(CONTENT_TYPE, content_type.clone());
+    }
+
+    Ok((
+        StatusCode::OK,
+        TypedHeader(CacheControl::new().with_max_age(Duration::from_secs(60))),
+        headers,
+        StreamBody::new(hold_permit(fallback.bytes_stream(), permit)),
+    )
+        .into_response())
+}
+
+async fn blur_handler(
+    State(state): State<Arc<RwLock<PhixivState>>>,
+    Path(path): Path<String>,
+    RawQuery(query): RawQuery,
+) -> Result<impl IntoResponse, PhixivError> {
+    if !BLUR_PATH_PREFIXES
+        .iter()
+        .any(|prefix| relative_path(&path).starts_with(prefix))
+    {
+        return Err(
+            StatusError::new(StatusCode::NOT_FOUND, "only resized images can be blurred").into(),
+        );
+    }
+
+    let response = fetch_upstream(&state, &path, query.as_deref())
+        .await?
+        .error_for_status()?;
+    let max_bytes = max_proxy_bytes().map_or(BLUR_MAX_BYTES, |max| max.min(BLUR_MAX_BYTES));
+    let bytes = read_limited(response, max_bytes).await?;
+
+    let sigma = helper::env_or("BLUR_SIGMA", 16.0);
+
+    let blurred = tokio::task::spawn_blocking(move || -> anyhow::Result<Vec<u8>> {
+        let mut limits = Limits::default();
+        limits.max_image_width = Some(BLUR_MAX_DECODE_DIMENSION);
+        limits.max_image_height = Some(BLUR_MAX_DECODE_DIMENSION);
+        limits.max_alloc = Some(BLUR_MAX_BYTES * 16);
+
+        let mut reader = Reader::new(Cursor::new(&bytes)).with_guessed_format()?;
+        reader.limits(limits);
+
+        let image = reader
+            .decode()?
+            .thumbnail(BLUR_MAX_DIMENSION, BLUR_MAX_DIMENSION)
+            .blur(sigma);
+
+        let mut output = Cursor::new(Vec::new());
+        image
+            .to_rgb8()
+            .write_to(&mut output, ImageOutputFormat::Jpeg(80))?;
+
+        Ok(output.into_inner())
+    })
+    .await??;
+
+    Ok((cache_control(), TypedHeader(ContentType::jpeg()), blurred))
+}
+
+async fn overloaded() -> impl IntoResponse {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        "too many concurrent image requests, try again later",
+    )
 }
 
 pub fn proxy_router(state: Arc<RwLock<PhixivState>>) -> Router<Arc<RwLock<PhixivState>>> {
+    let max_concurrency = helper::env_or("PROXY_MAX_CONCURRENCY", 512);
+    // Blurring decodes whole images, far fewer of those run at once than plain proxy requests.
+    let blur_max_concurrency = helper::env_or("BLUR_MAX_CONCURRENCY", 4);
+
     Router::new()
+        .route(
+            "/blur/*path",
+            get(blur_handler).layer(
+                ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(|_: BoxError| overloaded()))
+                    .load_shed()
+                    .layer(GlobalConcurrencyLimitLayer::new(blur_max_concurrency)),
+            ),
+        )
         .route("/*path", get(proxy_handler))
         .layer(middleware::from_fn_with_state(state, authorized_middleware))
+        .layer(middleware::from_fn(helper::get_only_middleware))
+        .layer(DefaultBodyLimit::max(EMBED_BODY_LIMIT))
+        .layer(Extension(ProxyPermits(Arc::new(Semaphore::new(
+            max_concurrency,
+        )))))
 }
@@ -0,0 +1,69 @@
+use std::{
+    env,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use reqwest::Client;
+use serde_json::json;
+
+use crate::helper;
+
+/// When the last report was sent, reports are dropped until `ERROR_WEBHOOK_INTERVAL` has passed.
+static LAST_REPORT: Mutex<Option<Instant>> = Mutex::new(None);
+
+fn report_interval() -> Duration {
+    Duration::from_secs(helper::env_or("ERROR_WEBHOOK_INTERVAL", 60))
+}
+
+/// Claims the next report slot, false while the previous report is still within the interval.
+fn try_claim_slot() -> bool {
+    let mut last_report = LAST_REPORT.lock().unwrap();
+
+    if last_report.is_some_and(|last| last.elapsed() < report_interval()) {
+        return false;
+    }
+
+    *last_report = Some(Instant::now());
+    true
+}
+
+/// Posts `error` to `ERROR_WEBHOOK_URL` in the background, does nothing when it is unset.
+pub fn report_error(client: &Client, illust_id: Option<&str>, error: &anyhow::Error) {
+    let Some(webhook_url) = env::var("ERROR_WEBHOOK_URL")
+        .ok()
+        .filter(|url| !url.is_empty())
+    else {
+        return;
+    };
+
+    if !try_claim_slot() {
+        return;
+    }
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or_default();
+
+    let payload = json!({
+        "illust_id": illust_id,
+        "error": format!("{error:#}"),
+        "timestamp": timestamp,
+    });
+
+    let client = client.clone();
+
+    tokio::spawn(async move {
+        let result = client
+            .post(&webhook_url)
+            .json(&payload)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+
+        if let Err(e) = result {
+            tracing::warn!("Failed to send error report: {e}");
+        }
+    });
+}
@@ -1,25 +1,76 @@
-use std::sync::Arc;
+use std::{env, sync::Arc, time::Duration};
 
+use anyhow::Context;
 use axum::{extract::State, middleware::Next, response::Response};
 use http::Request;
-use reqwest::Client;
+use reqwest::{Certificate, Client, Proxy};
 use tokio::sync::RwLock;
 
-use crate::{auth::PixivAuth, helper::PhixivError};
+use crate::{
+    auth::PixivAuth,
+    embed::RenderCache,
+    helper::{self, PhixivError},
+    pixiv::{load_pinned_artworks, ListingFlights, PinnedArtworks},
+};
 
 #[derive(Clone)]
 pub struct PhixivState {
     pub auth: PixivAuth,
     pub client: Client,
+    pub listing_flights: ListingFlights,
+    pub render_cache: RenderCache,
+    pub pinned_artworks: PinnedArtworks,
 }
 
 impl PhixivState {
     pub async fn login(refresh_token: String) -> anyhow::Result<Self> {
-        let client = Client::new();
+        let mut builder = Client::builder()
+            .pool_max_idle_per_host(helper::env_or("POOL_MAX_IDLE_PER_HOST", 32))
+            .pool_idle_timeout(Duration::from_secs(helper::env_or("POOL_IDLE_TIMEOUT", 90)))
+            .tcp_keepalive(Duration::from_secs(helper::env_or("TCP_KEEPALIVE", 60)));
+
+        // Routes every outbound request, pixiv and pximg alike, through an http(s) or socks5 proxy.
+        if let Some(proxy_url) = env::var("OUTBOUND_PROXY")
+            .ok()
+            .filter(|url| !url.is_empty())
+        {
+            let proxy = Proxy::all(&proxy_url)
+                .with_context(|| format!("OUTBOUND_PROXY {proxy_url} is not a valid proxy url"))?;
+            builder = builder.proxy(proxy);
+        }
+
+        // Trusts an additional root, for networks that intercept TLS with their own CA.
+        if let Some(ca_path) = env::var("EXTRA_CA_CERT")
+            .ok()
+            .filter(|path| !path.is_empty())
+        {
+            let pem = std::fs::read(&ca_path)
+                .with_context(|| format!("failed to read EXTRA_CA_CERT {ca_path}"))?;
+            let certificate = Certificate::from_pem(&pem)
+                .with_context(|| format!("EXTRA_CA_CERT {ca_path} is not a PEM certificate"))?;
+            builder = builder.add_root_certificate(certificate);
+        }
+
+        if helper::env_or("DANGER_ACCEPT_INVALID_CERTS", false) {
+            tracing::warn!(
+                "DANGER_ACCEPT_INVALID_CERTS is set, outbound TLS certificates are NOT verified"
+            );
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+
+        let client = builder.build()?;
+
+        let pinned_artworks = load_pinned_artworks()?;
 
         let auth = PixivAuth::login(&client, refresh_token).await?;
 
-        Ok(Self { auth, client })
+        Ok(Self {
+            auth,
+            client,
+            listing_flights: ListingFlights::default(),
+            render_cache: RenderCache::default(),
+            pinned_artworks,
+        })
     }
 
     async fn refresh(&mut self) -> anyhow::Result<()> {